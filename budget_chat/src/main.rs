@@ -2,77 +2,72 @@ use std::{
     collections::HashMap,
     fmt::Display,
     io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    net::TcpStream,
     sync::{mpsc, Arc, Mutex},
     thread,
 };
 
 type Clients = Arc<Mutex<HashMap<String, mpsc::Sender<String>>>>;
 
-fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").expect("bound port 80 on 0.0.0.0");
+fn main() -> std::io::Result<()> {
+    let config = protohackers_common::init();
     let clients: Clients = Arc::new(Mutex::new(HashMap::<String, mpsc::Sender<String>>::new()));
 
-    for stream in listener.incoming() {
-        let clients = Arc::clone(&clients);
-
-        thread::spawn(move || {
-            eprintln!("Opening connection");
+    protohackers_common::serve_threaded(&config, move |stream| {
+        handle_client(stream, Arc::clone(&clients))
+    })
+}
 
-            let stream = stream.expect("opened TCP stream");
-            let mut reader = BufReader::new(stream.try_clone().expect("cloned stream"));
-            let (sender, receiver) = mpsc::channel::<String>();
+fn handle_client(stream: TcpStream, clients: Clients) {
+    let mut reader = BufReader::new(stream.try_clone().expect("cloned stream"));
+    let (sender, receiver) = mpsc::channel::<String>();
 
-            writeln!(&stream, "Welcome to budgetchat! What shall I call you?").ok();
-            let name = match get_name(&mut reader, &clients) {
-                Ok(name) => {
-                    let mut clients = clients.lock().unwrap();
+    writeln!(&stream, "Welcome to budgetchat! What shall I call you?").ok();
+    let name = match get_name(&mut reader, &clients) {
+        Ok(name) => {
+            let mut clients = clients.lock().unwrap();
 
-                    let current_names = clients.keys().cloned().collect::<Vec<_>>().join(", ");
-                    writeln!(&stream, "* The room contains: {current_names}").ok();
+            let current_names = clients.keys().cloned().collect::<Vec<_>>().join(", ");
+            writeln!(&stream, "* The room contains: {current_names}").ok();
 
-                    clients.insert(name.to_owned(), sender);
-                    eprintln!("User {name} connected");
+            clients.insert(name.to_owned(), sender);
+            tracing::info!(name, "user connected");
 
-                    name
-                }
-                Err(reason) => {
-                    eprintln!("Could not join, {reason}");
-                    eprintln!("Closing connection");
+            name
+        }
+        Err(reason) => {
+            tracing::info!(reason, "could not join");
 
-                    return;
-                }
-            };
+            return;
+        }
+    };
 
-            eprintln!("Announcing {name}");
-            send_messages(format!("* {name} has entered the room"), &clients, &name);
+    tracing::debug!(name, "announcing");
+    send_messages(format!("* {name} has entered the room"), &clients, &name);
 
-            let msg_forwarder_thread = thread::spawn(move || {
-                for msg in receiver {
-                    writeln!(&stream, "{msg}").ok();
-                }
-            });
+    let msg_forwarder_thread = thread::spawn(move || {
+        for msg in receiver {
+            writeln!(&stream, "{msg}").ok();
+        }
+    });
 
-            let mut msg = String::new();
-            while let Ok(n) = reader.read_line(&mut msg) {
-                if n == 0 {
-                    eprintln!("Reached EOF for user {name}");
-                    break;
-                }
+    let mut msg = String::new();
+    while let Ok(n) = reader.read_line(&mut msg) {
+        if n == 0 {
+            tracing::debug!(name, "reached EOF");
+            break;
+        }
 
-                send_messages(format!("[{name}] {}", msg.trim_end()), &clients, &name);
-                msg.clear();
-            }
+        send_messages(format!("[{name}] {}", msg.trim_end()), &clients, &name);
+        msg.clear();
+    }
 
-            clients.lock().unwrap().remove(&name);
-            msg_forwarder_thread.join().ok();
+    clients.lock().unwrap().remove(&name);
+    msg_forwarder_thread.join().ok();
 
-            send_messages(format!("* {name} has left the room"), &clients, &name);
+    send_messages(format!("* {name} has left the room"), &clients, &name);
 
-            eprintln!("Disconnected user {name}");
-            eprintln!("Closing connection");
-        });
-    }
+    tracing::info!(name, "user disconnected");
 }
 
 fn get_name(reader: &mut BufReader<TcpStream>, clients: &Clients) -> Result<String, &'static str> {
@@ -99,7 +94,7 @@ fn get_name(reader: &mut BufReader<TcpStream>, clients: &Clients) -> Result<Stri
 fn send_messages(msg: impl Display, clients: &Clients, sender: &str) {
     for (name, client) in clients.lock().unwrap().iter_mut() {
         match name == sender {
-            true => eprintln!("Skipping sending to {sender}"),
+            true => tracing::trace!(sender, "skipping sender"),
             false => {
                 client.send(msg.to_string()).ok();
             }
@@ -3,7 +3,7 @@ use std::fmt::Display;
 use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::tcp::OwnedReadHalf;
-use tokio::net::TcpListener;
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
 use tokio::task;
@@ -11,92 +11,90 @@ use tokio::task;
 type Clients = Arc<Mutex<HashMap<String, UnboundedSender<String>>>>;
 
 #[tokio::main]
-async fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+async fn main() -> std::io::Result<()> {
+    let config = protohackers_common::init();
     let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
 
-    loop {
-        let (tcp_stream, _) = listener.accept().await.unwrap();
-        let clients = Arc::clone(&clients);
-
-        task::spawn(async move {
-            let (reader, writer) = tcp_stream.into_split();
-            let mut reader = BufReader::new(reader);
-            let mut writer = BufWriter::new(writer);
-            let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    protohackers_common::serve(&config, move |tcp_stream| {
+        handle_client(tcp_stream, Arc::clone(&clients))
+    })
+    .await
+}
 
+async fn handle_client(tcp_stream: TcpStream, clients: Clients) {
+    let (reader, writer) = tcp_stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+
+    writer
+        .write_all(b"Welcome to budgetchat async? What shall I call you?\n")
+        .await
+        .unwrap();
+    writer.flush().await.unwrap();
+
+    let name = match get_name(&mut reader, &clients).await {
+        Ok(name) => {
+            let current_names = clients
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ");
             writer
-                .write_all(b"Welcome to budgetchat async? What shall I call you?\n")
+                .write_all(format!("* The room contains: {current_names}\n").as_bytes())
                 .await
                 .unwrap();
             writer.flush().await.unwrap();
 
-            let name = match get_name(&mut reader, &clients).await {
-                Ok(name) => {
-                    let current_names = clients
-                        .lock()
-                        .unwrap()
-                        .keys()
-                        .cloned()
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    writer
-                        .write_all(format!("* The room contains: {current_names}\n").as_bytes())
-                        .await
-                        .unwrap();
-                    writer.flush().await.unwrap();
-
-                    clients.lock().unwrap().insert(name.to_owned(), sender);
-                    eprintln!("User {name} connected");
-
-                    name
-                }
-                Err(reason) => {
-                    eprintln!("Could not join, {reason}");
-                    eprintln!("Closing connection");
-
-                    return;
-                }
-            };
-
-            eprintln!("Announcing {name}");
-            send_messages(format!("* {name} has entered the room"), &clients, &name);
-
-            let msg_forwarder_thread = task::spawn(async move {
-                loop {
-                    let msg = receiver.recv().await.unwrap();
-                    writer
-                        .write_all(format!("{msg}\n").as_bytes())
-                        .await
-                        .unwrap();
-                    writer.flush().await.ok();
-                }
-            });
-
-            let mut msg = String::new();
-            loop {
-                if let Ok(n) = reader.read_line(&mut msg).await {
-                    eprintln!("{:?}", msg.as_bytes());
-
-                    if n == 0 {
-                        eprintln!("Reached EOF for user {name}");
-                        break;
-                    }
-
-                    send_messages(format!("[{name}] {}", msg.trim_end()), &clients, &name);
-                    msg.clear();
-                }
-            }
+            clients.lock().unwrap().insert(name.to_owned(), sender);
+            tracing::info!(name, "user connected");
+
+            name
+        }
+        Err(reason) => {
+            tracing::info!(reason, "could not join");
+
+            return;
+        }
+    };
+
+    tracing::debug!(name, "announcing");
+    send_messages(format!("* {name} has entered the room"), &clients, &name);
+
+    let msg_forwarder_thread = task::spawn(async move {
+        loop {
+            let msg = receiver.recv().await.unwrap();
+            writer
+                .write_all(format!("{msg}\n").as_bytes())
+                .await
+                .unwrap();
+            writer.flush().await.ok();
+        }
+    });
 
-            clients.lock().unwrap().remove(&name);
-            msg_forwarder_thread.await.ok();
+    let mut msg = String::new();
+    loop {
+        if let Ok(n) = reader.read_line(&mut msg).await {
+            tracing::trace!(bytes = ?msg.as_bytes(), "received");
 
-            send_messages(format!("* {name} has left the room"), &clients, &name);
+            if n == 0 {
+                tracing::debug!(name, "reached EOF");
+                break;
+            }
 
-            eprintln!("Disconnected user {name}");
-            eprintln!("Closing connection");
-        });
+            send_messages(format!("[{name}] {}", msg.trim_end()), &clients, &name);
+            msg.clear();
+        }
     }
+
+    clients.lock().unwrap().remove(&name);
+    msg_forwarder_thread.await.ok();
+
+    send_messages(format!("* {name} has left the room"), &clients, &name);
+
+    tracing::info!(name, "user disconnected");
 }
 
 async fn get_name(
@@ -105,7 +103,7 @@ async fn get_name(
 ) -> Result<String, &'static str> {
     let mut name = String::new();
     reader.read_line(&mut name).await.unwrap();
-    eprintln!("{:?}", name.as_bytes());
+    tracing::trace!(bytes = ?name.as_bytes(), "received name");
     let name = name.trim();
 
     if name.is_empty() {
@@ -127,7 +125,7 @@ async fn get_name(
 fn send_messages(msg: impl Display, clients: &Clients, sender: &str) {
     for (name, client) in clients.lock().unwrap().iter_mut() {
         match name == sender {
-            true => eprintln!("Skipping sending to {sender}"),
+            true => tracing::trace!(sender, "skipping sender"),
             false => {
                 client.send(msg.to_string()).ok();
             }
@@ -6,7 +6,6 @@ use tokio::net::{
     TcpStream,
 };
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
-use tracing;
 
 #[derive(Clone, Debug)]
 pub enum CipherOp {
@@ -202,7 +201,7 @@ mod tests {
     #[test]
     fn ex_1() {
         let input: [u8; 5] = [0x68, 0x65, 0x6c, 0x6c, 0x6f];
-        let cipherspec = vec![CipherOp::XorN(1), CipherOp::Rev];
+        let cipherspec = [CipherOp::XorN(1), CipherOp::Rev];
 
         let mut res = Vec::with_capacity(5);
 
@@ -220,7 +219,7 @@ mod tests {
     #[test]
     fn ex_2() {
         let input: [u8; 5] = [0x68, 0x65, 0x6c, 0x6c, 0x6f];
-        let cipherspec = vec![CipherOp::AddPos, CipherOp::AddPos];
+        let cipherspec = [CipherOp::AddPos, CipherOp::AddPos];
 
         let mut res = Vec::with_capacity(5);
 
@@ -246,7 +245,7 @@ mod tests {
         let output_1 = [0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee];
         let output_2 = [0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e];
 
-        let cipherspec = vec![CipherOp::XorN(123), CipherOp::AddPos, CipherOp::Rev];
+        let cipherspec = [CipherOp::XorN(123), CipherOp::AddPos, CipherOp::Rev];
 
         let res = |input: &[u8], decoding: bool, offset: usize| {
             let mut res = Vec::with_capacity(14);
@@ -0,0 +1,139 @@
+//! Deleting and taking jobs, with the indexed queues against the heap scan they replaced.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use job_centre_async::{queue::Queued, work::Job};
+use std::collections::{BinaryHeap, HashMap};
+
+const QUEUES: usize = 10;
+
+fn job(id: usize) -> Job {
+    Job::new(id, format!("q{}", id % QUEUES), id * 7919 % 1000)
+}
+
+/// The old way: jobs straight in their queues' heaps, which were searched through to delete.
+#[derive(Default)]
+struct Scan(HashMap<String, BinaryHeap<ByPri>>);
+
+struct ByPri(Job);
+
+impl Ord for ByPri {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.pri().cmp(&other.0.pri())
+    }
+}
+
+impl PartialOrd for ByPri {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ByPri {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.pri() == other.0.pri()
+    }
+}
+
+impl Eq for ByPri {}
+
+impl Scan {
+    fn push(&mut self, job: Job) {
+        self.0
+            .entry(job.queue().to_string())
+            .or_default()
+            .push(ByPri(job));
+    }
+
+    fn next_best(&mut self, candidates: &[String]) -> Option<Job> {
+        self.0
+            .iter_mut()
+            .filter(|(name, queue)| candidates.contains(name) && !queue.is_empty())
+            .max_by(|l, r| l.1.peek().unwrap().cmp(r.1.peek().unwrap()))
+            .and_then(|(_, queue)| queue.pop())
+            .map(|job| job.0)
+    }
+
+    fn del(&mut self, id: usize) -> Option<()> {
+        let name = self.0.iter().find_map(|(name, queue)| {
+            queue
+                .iter()
+                .any(|job| job.0.id() == id)
+                .then_some(name.to_owned())
+        })?;
+
+        self.0
+            .get_mut(&name)
+            .unwrap()
+            .retain(|job| job.0.id() != id);
+        Some(())
+    }
+}
+
+fn filled(jobs: usize) -> (Scan, Queued) {
+    let mut scan = Scan::default();
+    let mut queued = Queued::new();
+    for id in 0..jobs {
+        scan.push(job(id));
+        queued.push(job(id));
+    }
+
+    (scan, queued)
+}
+
+/// Delete the oldest job and put a new one, so the queues stay the same size.
+fn delete(c: &mut Criterion) {
+    let mut group = c.benchmark_group("delete");
+
+    for jobs in [1_000, 10_000, 100_000] {
+        let (mut scan, mut queued) = filled(jobs);
+
+        // Criterion runs each routine more than once, so the ids carry on between runs
+        let mut next = jobs;
+        group.bench_function(BenchmarkId::new("heap_scan", jobs), |b| {
+            b.iter(|| {
+                scan.del(next - jobs).unwrap();
+                scan.push(job(next));
+                next += 1;
+            })
+        });
+
+        let mut next = jobs;
+        group.bench_function(BenchmarkId::new("indexed", jobs), |b| {
+            b.iter(|| {
+                queued.remove(next - jobs).unwrap();
+                queued.push(job(next));
+                next += 1;
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Take the best job from a few queues, then put it back.
+fn next_best(c: &mut Criterion) {
+    let mut group = c.benchmark_group("next_best");
+    let candidates = ["q1", "q4", "q7"].map(String::from);
+
+    for jobs in [1_000, 10_000, 100_000] {
+        let (mut scan, mut queued) = filled(jobs);
+
+        group.bench_function(BenchmarkId::new("heap_scan", jobs), |b| {
+            b.iter(|| {
+                let job = scan.next_best(&candidates).unwrap();
+                scan.push(job);
+            })
+        });
+        group.bench_function(BenchmarkId::new("indexed", jobs), |b| {
+            b.iter(|| {
+                let job = queued.pop_best(&candidates).unwrap();
+                queued.push(job);
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, delete, next_best);
+criterion_main!(benches);
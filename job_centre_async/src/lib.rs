@@ -0,0 +1,7 @@
+//! The job centre's queues and the log they're kept in, shared by the server (this crate's main
+//! binary) and its benchmarks.
+
+pub mod queue;
+pub mod res;
+pub mod wal;
+pub mod work;
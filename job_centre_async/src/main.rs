@@ -1,33 +1,105 @@
-use std::sync::{
-    atomic::{AtomicUsize, Ordering::SeqCst},
-    Arc, Mutex,
+use clap::Parser;
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
-    sync::mpsc,
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{mpsc, oneshot, Notify},
+    time::Instant,
 };
 
-use work::{InFlightQueue, JobQueues};
+use job_centre_async::{
+    res, wal,
+    work::{self, Claim, InFlightQueue, Job, JobQueues},
+};
+
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    server: protohackers_common::Config,
+
+    /// Directory the job log and snapshots are kept in
+    #[arg(long, env = "JOBS_DATA_DIR", default_value = "./jobs")]
+    data_dir: PathBuf,
+
+    /// Seconds between snapshots, each of which lets the log start afresh
+    #[arg(long, env = "JOBS_SNAPSHOT_INTERVAL", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_interval: u64,
+
+    /// Flush every logged change to disk, so none are lost even if the machine goes down
+    #[arg(long, env = "JOBS_SYNC")]
+    sync: bool,
+
+    /// Set aside a queue's jobs as dead letters once they've been handed out this many times
+    /// without being deleted, as `queue:attempts`. Other queues' jobs are retried indefinitely
+    #[arg(
+        long = "max-attempts",
+        env = "JOBS_MAX_ATTEMPTS",
+        value_delimiter = ','
+    )]
+    max_attempts: Vec<work::QueueLimit>,
+
+    /// Hand out no more than this many of a queue's jobs at once, as `queue:jobs`
+    #[arg(
+        long = "max-in-flight",
+        env = "JOBS_MAX_IN_FLIGHT",
+        value_delimiter = ','
+    )]
+    max_in_flight: Vec<work::QueueLimit>,
+
+    /// Hand out no more than this many of a queue's jobs a second, as `queue:jobs`
+    #[arg(long = "max-rate", env = "JOBS_MAX_RATE", value_delimiter = ',')]
+    max_rate: Vec<work::QueueLimit>,
+
+    /// Raise a queued job's priority by one for every this many seconds it waits, so jobs in busy
+    /// queues can't be starved by more important ones
+    #[arg(long, env = "JOBS_AGING", value_parser = clap::value_parser!(u64).range(1..))]
+    aging: Option<u64>,
+
+    /// Seconds a deleted job's dedup key keeps finding it, so late retries of its put are ignored
+    #[arg(long, env = "JOBS_DEDUP_WINDOW", default_value_t = 300)]
+    dedup_window: u64,
+}
 
 #[tokio::main]
-async fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+async fn main() -> std::io::Result<()> {
+    let args: Args = protohackers_common::init_with();
+
+    let (wal, recovered) = wal::Wal::open(&args.data_dir, args.sync)?;
 
     let next_client_id = AtomicUsize::new(0);
-    let job_queues = Arc::new(Mutex::new(work::JobQueues::new()));
+    let limits = work::Limits {
+        max_attempts: args.max_attempts,
+        max_in_flight: args.max_in_flight,
+        max_rate: args.max_rate,
+        aging: args.aging.map(Duration::from_secs),
+        dedup_window: Duration::from_secs(args.dedup_window),
+    };
+    let job_queues = Arc::new(Mutex::new(work::JobQueues::new(wal, recovered, limits)));
     let in_flight_queue = Arc::new(Mutex::new(work::InFlightQueue::new()));
 
-    loop {
-        let (tcp_stream, _addr) = listener.accept().await.unwrap();
+    tokio::spawn(schedule(Arc::clone(&job_queues)));
+    tokio::spawn(snapshot(
+        Arc::clone(&job_queues),
+        Arc::clone(&in_flight_queue),
+        Duration::from_secs(args.snapshot_interval),
+    ));
 
-        tokio::spawn(handle_client(
+    protohackers_common::serve(&args.server, move |tcp_stream| {
+        handle_client(
             tcp_stream,
             next_client_id.fetch_add(1, SeqCst),
             Arc::clone(&job_queues),
             Arc::clone(&in_flight_queue),
-        ));
-    }
+        )
+    })
+    .await
 }
 
 async fn handle_client(
@@ -49,75 +121,189 @@ async fn handle_client(
         buf.clear();
 
         if let Ok(0) = reader.read_line(&mut buf).await {
-            eprintln!("EOF");
+            tracing::debug!(client_id, "EOF");
             break;
         }
 
         match serde_json::from_str::<req::Request>(buf.trim()) {
-            Ok(req::Request::Put { queue, pri, job }) => {
-                let id = job_queues.lock().unwrap().add(queue, job, pri);
-                client_write_tx
-                    .send(res::Response::Put {
-                        status: res::ResponseStatus::Ok,
-                        id,
-                    })
-                    .await
-                    .ok();
-            }
-            Ok(req::Request::Get { queues, wait }) => {
-                let job = job_queues.lock().unwrap().next_best(&queues);
-                match job {
-                    Some(job) => {
-                        eprintln!("IMMEDIATE {job:?}");
-                        in_flight_queue.lock().unwrap().add(job.clone(), client_id);
-                        client_write_tx.send(res::Response::from(job)).await.ok();
-                    }
-                    None if wait == Some(true) => {
-                        tokio::spawn(handle_wait_for_job(
-                            queues.clone(),
-                            Arc::clone(&job_queues),
-                            Arc::clone(&in_flight_queue),
-                            mpsc::Sender::clone(&client_write_tx),
-                            client_id,
-                        ));
+            Ok(req::Request::Put {
+                queue,
+                pri,
+                job,
+                timeout,
+                delay,
+                run_at,
+                after,
+                dedup_key,
+            }) => {
+                let res = match (delay, run_at) {
+                    (Some(_), Some(_)) => res::Response::Err {
+                        status: res::ResponseStatus::Error,
+                    },
+                    (delay, run_at) => {
+                        let run_at = delay.map(|delay| work::now() + delay).or(run_at);
+                        let added = job_queues.lock().unwrap().add(work::NewJob {
+                            queue,
+                            job,
+                            pri,
+                            timeout,
+                            run_at,
+                            after,
+                            dedup_key,
+                        });
+
+                        match added {
+                            Ok((id, run_at)) => res::Response::Put {
+                                status: res::ResponseStatus::Ok,
+                                id,
+                                run_at,
+                            },
+                            Err(_) => res::Response::Err {
+                                status: res::ResponseStatus::Error,
+                            },
+                        }
                     }
-                    None => {
-                        client_write_tx
-                            .send(res::Response::Err {
-                                status: res::ResponseStatus::NoJob,
-                            })
-                            .await
-                            .ok();
+                };
+
+                client_write_tx.send(res).await.ok();
+            }
+            Ok(req::Request::Get {
+                queues,
+                wait,
+                timeout,
+            }) => {
+                let res = {
+                    let mut in_flight = in_flight_queue.lock().unwrap();
+                    let mut queued = job_queues.lock().unwrap();
+
+                    match queued.next_best(&queues) {
+                        Ok(Some(job)) => {
+                            tracing::debug!(?job, "immediate");
+                            Some(hand_out(
+                                job,
+                                client_id,
+                                timeout,
+                                &mut in_flight,
+                                &job_queues,
+                                &in_flight_queue,
+                            ))
+                        }
+                        Ok(None) if wait == Some(true) => {
+                            // Under the same lock as the miss, so no put can slip in between
+                            let (waiter, wake) = queued.wait(client_id, queues);
+                            tokio::spawn(handle_wait_for_job(
+                                waiter,
+                                wake,
+                                Arc::clone(&job_queues),
+                                Arc::clone(&in_flight_queue),
+                                mpsc::Sender::clone(&client_write_tx),
+                                client_id,
+                                timeout,
+                            ));
+                            None
+                        }
+                        Ok(None) => Some(res::Response::Err {
+                            status: res::ResponseStatus::NoJob,
+                        }),
+                        Err(_) => Some(res::Response::Err {
+                            status: res::ResponseStatus::Error,
+                        }),
                     }
+                };
+
+                if let Some(res) = res {
+                    client_write_tx.send(res).await.ok();
                 }
             }
             Ok(req::Request::Delete { id }) => {
-                let job_queues_deleted = job_queues.lock().unwrap().del(id).is_some();
-                let in_flight_deleted = in_flight_queue.lock().unwrap().del(id).is_some();
+                let deleted = {
+                    let mut in_flight = in_flight_queue.lock().unwrap();
+                    let mut job_queues = job_queues.lock().unwrap();
 
-                let status = if job_queues_deleted || in_flight_deleted {
-                    res::ResponseStatus::Ok
-                } else {
-                    res::ResponseStatus::NoJob
+                    match job_queues.del(id) {
+                        Ok(None) => in_flight.del(&mut job_queues, id),
+                        deleted => deleted,
+                    }
                 };
 
-                client_write_tx
-                    .send(res::Response::Delete { status })
-                    .await
-                    .ok();
+                let res = match deleted {
+                    Ok(Some(())) => res::Response::Delete {
+                        status: res::ResponseStatus::Ok,
+                    },
+                    Ok(None) => res::Response::Delete {
+                        status: res::ResponseStatus::NoJob,
+                    },
+                    Err(_) => res::Response::Err {
+                        status: res::ResponseStatus::Error,
+                    },
+                };
+
+                client_write_tx.send(res).await.ok();
             }
-            Ok(req::Request::Abort { id }) => {
-                let res = in_flight_queue.lock().unwrap().abort(
-                    &mut job_queues.lock().unwrap(),
+            Ok(req::Request::Abort { id, fail }) => {
+                let res = {
+                    let mut in_flight = in_flight_queue.lock().unwrap();
+                    let mut job_queues = job_queues.lock().unwrap();
+
+                    if fail {
+                        in_flight.fail(&mut job_queues, id, client_id)
+                    } else {
+                        Ok(in_flight.abort(&mut job_queues, id, client_id))
+                    }
+                };
+
+                let res = match res {
+                    Ok(Ok(Some(()))) => res::Response::Abort {
+                        status: res::ResponseStatus::Ok,
+                    },
+                    Ok(Ok(None)) => res::Response::Abort {
+                        status: res::ResponseStatus::NoJob,
+                    },
+                    Ok(Err(_)) | Err(_) => res::Response::Err {
+                        status: res::ResponseStatus::Error,
+                    },
+                };
+
+                client_write_tx.send(res).await.ok();
+            }
+            Ok(req::Request::Touch { id, timeout }) => {
+                let res = in_flight_queue.lock().unwrap().touch(
                     id,
                     client_id,
+                    timeout.map(Duration::from_secs),
                 );
 
                 let res = match res {
-                    Ok(Some(())) => res::Response::Abort {
+                    Ok(Some(())) => res::Response::Touch {
+                        status: res::ResponseStatus::Ok,
+                    },
+                    Ok(None) => res::Response::Touch {
+                        status: res::ResponseStatus::NoJob,
+                    },
+                    Err(_) => res::Response::Err {
+                        status: res::ResponseStatus::Error,
+                    },
+                };
+
+                client_write_tx.send(res).await.ok();
+            }
+            Ok(req::Request::Inspect { queue }) => {
+                let jobs = job_queues.lock().unwrap().inspect(&queue);
+
+                client_write_tx
+                    .send(res::Response::Inspect {
+                        status: res::ResponseStatus::Ok,
+                        jobs,
+                    })
+                    .await
+                    .ok();
+            }
+            Ok(req::Request::Requeue { id }) => {
+                let res = match job_queues.lock().unwrap().requeue(id) {
+                    Ok(Some(())) => res::Response::Requeue {
                         status: res::ResponseStatus::Ok,
                     },
-                    Ok(None) => res::Response::Abort {
+                    Ok(None) => res::Response::Requeue {
                         status: res::ResponseStatus::NoJob,
                     },
                     Err(_) => res::Response::Err {
@@ -127,8 +313,44 @@ async fn handle_client(
 
                 client_write_tx.send(res).await.ok();
             }
+            Ok(req::Request::Stats) => {
+                let res = {
+                    let in_flight = in_flight_queue.lock().unwrap();
+                    res::Response::Stats {
+                        status: res::ResponseStatus::Ok,
+                        queues: job_queues.lock().unwrap().stats(),
+                        in_flight: in_flight.per_client(),
+                    }
+                };
+
+                client_write_tx.send(res).await.ok();
+            }
+            Ok(req::Request::Peek { queues }) => {
+                let res = match job_queues.lock().unwrap().peek(&queues) {
+                    Some(job) => res::Response::Peek {
+                        status: res::ResponseStatus::Ok,
+                        job,
+                    },
+                    None => res::Response::Err {
+                        status: res::ResponseStatus::NoJob,
+                    },
+                };
+
+                client_write_tx.send(res).await.ok();
+            }
+            Ok(req::Request::ListInFlight) => {
+                let jobs = in_flight_queue.lock().unwrap().list(Instant::now());
+
+                client_write_tx
+                    .send(res::Response::InFlight {
+                        status: res::ResponseStatus::Ok,
+                        jobs,
+                    })
+                    .await
+                    .ok();
+            }
             Err(e) => {
-                eprint!("Deserialize Err: {e}");
+                tracing::warn!(err = %e, "deserialize failed");
                 client_write_tx
                     .send(res::Response::Err {
                         status: res::ResponseStatus::Error,
@@ -139,12 +361,75 @@ async fn handle_client(
         }
     }
 
-    eprintln!("Disconnecting");
+    tracing::debug!(client_id, "disconnecting");
 
-    in_flight_queue
-        .lock()
-        .unwrap()
-        .cleanup(&mut job_queues.lock().unwrap(), client_id);
+    // Stop waiting first, so none of the client's own jobs are handed back to it
+    let mut in_flight = in_flight_queue.lock().unwrap();
+    let mut job_queues = job_queues.lock().unwrap();
+    job_queues.cancel(client_id);
+    in_flight.cleanup(&mut job_queues, client_id);
+}
+
+/// Make scheduled jobs visible as they fall due, and hand out rate limited jobs to waiting clients
+/// as their limits allow.
+async fn schedule(job_queues: Arc<Mutex<JobQueues>>) {
+    let scheduled = job_queues.lock().unwrap().scheduler();
+
+    loop {
+        let (due, unthrottled) = {
+            let mut job_queues = job_queues.lock().unwrap();
+            (
+                job_queues.release_due(work::now()),
+                job_queues.unthrottle(Instant::now()),
+            )
+        };
+        let next = due
+            .map(|run_at| Instant::now() + work::until(run_at))
+            .into_iter()
+            .chain(unthrottled)
+            .min();
+
+        match next {
+            Some(next) => {
+                tokio::select! {
+                    _ = scheduled.notified() => {}
+                    _ = tokio::time::sleep_until(next) => {}
+                }
+            }
+            None => scheduled.notified().await,
+        }
+    }
+}
+
+/// Snapshot every job every `every`, starting now.
+async fn snapshot(
+    job_queues: Arc<Mutex<JobQueues>>,
+    in_flight_queue: Arc<Mutex<InFlightQueue>>,
+    every: Duration,
+) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+
+        let job_queues = Arc::clone(&job_queues);
+        let in_flight_queue = Arc::clone(&in_flight_queue);
+        let res = tokio::task::spawn_blocking(move || {
+            // Just the jobs are copied under the locks, and the snapshot written after
+            let checkpoint = {
+                let in_flight_queue = in_flight_queue.lock().unwrap();
+                job_queues.lock().unwrap().snapshot(&in_flight_queue)?
+            };
+            checkpoint.write()
+        })
+        .await;
+
+        match res {
+            Ok(Ok(())) => tracing::debug!("snapshotted jobs"),
+            Ok(Err(err)) => tracing::error!(%err, "snapshot failed"),
+            Err(err) => tracing::error!(%err, "snapshot panicked"),
+        }
+    }
 }
 
 async fn handle_client_write(
@@ -161,26 +446,103 @@ async fn handle_client_write(
     }
 }
 
+/// See `waiter` through to the one job it's handed, unless its client goes away first.
 async fn handle_wait_for_job(
-    scope_queues: Vec<String>,
+    waiter: usize,
+    wake: Arc<Notify>,
     job_queues: Arc<Mutex<JobQueues>>,
     in_flight_queue: Arc<Mutex<InFlightQueue>>,
     client_write_tx: mpsc::Sender<res::Response>,
     client_id: usize,
+    timeout: Option<u64>,
 ) {
-    let mut subscriber = job_queues.lock().unwrap().subscriber();
-    while let Ok(queue) = subscriber.recv().await {
-        if scope_queues.contains(&queue) {
-            let job = job_queues.lock().unwrap().next_best(&[queue]);
-            if let Some(job) = job {
-                eprintln!("TASK {job:?}");
-                in_flight_queue.lock().unwrap().add(job.clone(), client_id);
-                client_write_tx.send(res::Response::from(job)).await.ok();
+    loop {
+        wake.notified().await;
+
+        // Claimed and put in flight together, so a disconnect sees the job in one place or other
+        let res = {
+            let mut in_flight = in_flight_queue.lock().unwrap();
+            let claim = job_queues.lock().unwrap().claim(waiter);
+
+            match claim {
+                Ok(Claim::Waiting) => continue,
+                Ok(Claim::Cancelled) => return,
+                Err(_) => res::Response::Err {
+                    status: res::ResponseStatus::Error,
+                },
+                Ok(Claim::Ready(job)) => {
+                    tracing::debug!(?job, "waited");
+                    hand_out(
+                        job,
+                        client_id,
+                        timeout,
+                        &mut in_flight,
+                        &job_queues,
+                        &in_flight_queue,
+                    )
+                }
             }
+        };
+
+        client_write_tx.send(res).await.ok();
+        return;
+    }
+}
+
+/// Put `job` in flight with the client, for `timeout` seconds or the job's own timeout if either
+/// is set, and return the response handing it over.
+fn hand_out(
+    job: Job,
+    client_id: usize,
+    timeout: Option<u64>,
+    in_flight: &mut InFlightQueue,
+    job_queues: &Arc<Mutex<JobQueues>>,
+    in_flight_queue: &Arc<Mutex<InFlightQueue>>,
+) -> res::Response {
+    let timeout = timeout.or(job.timeout()).map(Duration::from_secs);
+    let removed = in_flight.add(job.clone(), client_id, timeout);
+
+    if timeout.is_some() {
+        tokio::spawn(handle_lease(
+            job.id(),
+            removed,
+            Arc::clone(job_queues),
+            Arc::clone(in_flight_queue),
+        ));
+    }
+
+    res::Response::from(job)
+}
+
+/// Return job `id` to its queue if its lease runs out before it's deleted or aborted.
+async fn handle_lease(
+    id: usize,
+    mut removed: oneshot::Receiver<()>,
+    job_queues: Arc<Mutex<JobQueues>>,
+    in_flight_queue: Arc<Mutex<InFlightQueue>>,
+) {
+    loop {
+        // A touch moves the deadline on, so look again each time it passes
+        let deadline = in_flight_queue.lock().unwrap().deadline(id);
+        let Some(deadline) = deadline else {
+            return;
+        };
+
+        tokio::select! {
+            _ = &mut removed => return,
+            _ = tokio::time::sleep_until(deadline) => {}
+        }
+
+        let expired = in_flight_queue.lock().unwrap().expire(
+            &mut job_queues.lock().unwrap(),
+            id,
+            Instant::now(),
+        );
+        if expired {
+            tracing::debug!(id, "lease expired");
+            return;
         }
     }
 }
 
 mod req;
-mod res;
-mod work;
@@ -0,0 +1,354 @@
+//! The jobs visible in their queues, waiting to be handed out.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{res::QueueStats, work::Job};
+
+/// Queued jobs, found by id through an index and by priority through each queue's heap. Removing
+/// a job by id leaves its heap entry behind as a tombstone, which is skipped once it reaches the
+/// top, so every operation is logarithmic at worst.
+///
+/// Jobs of equal priority come out oldest first. With aging, a job's priority also goes up by one
+/// for every so long it waits, so none wait forever behind a stream of more important ones.
+#[derive(Debug)]
+pub struct Queued {
+    jobs: HashMap<usize, Job>,
+    queues: HashMap<String, Queue>,
+    aging: Option<Duration>,
+    /// What the time waited is measured from
+    epoch: Instant,
+}
+
+impl Default for Queued {
+    fn default() -> Self {
+        Self {
+            jobs: HashMap::new(),
+            queues: HashMap::new(),
+            aging: None,
+            epoch: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    heap: BinaryHeap<Entry>,
+    /// Entries in `heap` whose jobs were removed
+    tombstones: usize,
+    /// Jobs in the queue, not counting tombstones
+    len: usize,
+    /// How many jobs there are at each priority, which with aging isn't the heap's order
+    pris: BTreeMap<usize, usize>,
+}
+
+/// A job's place in its queue's heap, best first by rank, then lowest id.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    /// The job's priority, less however long it had already waited when pushed if there's aging.
+    /// Every job ages at the same rate, so ranks compare the same however long they then wait.
+    rank: i128,
+    pri: usize,
+    id: usize,
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.rank
+            .cmp(&other.rank)
+            .then_with(|| other.id.cmp(&self.id))
+    }
+}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl Queued {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues where a job's priority goes up by one for each `per` it waits.
+    pub fn with_aging(per: Duration) -> Self {
+        Self {
+            aging: Some(per),
+            ..Self::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// How many jobs are in `queue`.
+    pub fn len_of(&self, queue: &str) -> usize {
+        self.queues.get(queue).map_or(0, |queue| queue.len)
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        self.jobs.contains_key(&id)
+    }
+
+    /// Every queued job, in no particular order.
+    pub fn jobs(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.values()
+    }
+
+    pub fn push(&mut self, job: Job) {
+        self.push_at(job, Instant::now());
+    }
+
+    fn push_at(&mut self, job: Job, now: Instant) {
+        let rank = match self.aging {
+            Some(per) => {
+                let waited = now.saturating_duration_since(self.epoch).as_nanos() as i128;
+                job.pri() as i128 * per.as_nanos().max(1) as i128 - waited
+            }
+            None => job.pri() as i128,
+        };
+        let entry = Entry {
+            rank,
+            pri: job.pri(),
+            id: job.id(),
+        };
+
+        let queue = self.queues.entry(job.queue().to_string()).or_default();
+        queue.heap.push(entry);
+        queue.len += 1;
+        *queue.pris.entry(entry.pri).or_default() += 1;
+
+        self.jobs.insert(entry.id, job);
+    }
+
+    /// Take the highest priority job from any of the `candidates` queues.
+    pub fn pop_best<S: AsRef<str>>(&mut self, candidates: &[S]) -> Option<Job> {
+        let (name, _) = self.best(candidates)?;
+
+        let queue = self.queues.get_mut(name.as_ref())?;
+        let entry = queue.heap.pop()?;
+        queue.len -= 1;
+        queue.forget_pri(entry.pri);
+
+        self.jobs.remove(&entry.id)
+    }
+
+    /// The job [`Self::pop_best`] would take, left where it is.
+    pub fn peek_best(&mut self, candidates: &[String]) -> Option<&Job> {
+        let (_, entry) = self.best(candidates)?;
+
+        self.jobs.get(&entry.id)
+    }
+
+    /// How many jobs each queue with any in has, and the highest priority among them.
+    pub fn stats(&self) -> BTreeMap<String, QueueStats> {
+        self.queues
+            .iter()
+            .filter_map(|(name, queue)| {
+                let (&max_pri, _) = queue.pris.last_key_value()?;
+                let stats = QueueStats {
+                    len: queue.len,
+                    max_pri,
+                };
+
+                Some((name.clone(), stats))
+            })
+            .collect()
+    }
+
+    fn best<'a, S: AsRef<str>>(&mut self, candidates: &'a [S]) -> Option<(&'a S, Entry)> {
+        let Self { jobs, queues, .. } = self;
+
+        candidates
+            .iter()
+            .filter_map(|name| Some((name, *queues.get_mut(name.as_ref())?.peek(jobs)?)))
+            .max_by_key(|(_, entry)| *entry)
+    }
+
+    /// Take job `id` from its queue.
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        let job = self.jobs.remove(&id)?;
+
+        if let Some(queue) = self.queues.get_mut(job.queue()) {
+            queue.len -= 1;
+            queue.forget_pri(job.pri());
+            queue.tombstones += 1;
+
+            // Sweep once tombstones are most of the heap, so it can't fill up with them
+            if queue.tombstones * 2 > queue.heap.len() {
+                queue.heap.retain(|entry| self.jobs.contains_key(&entry.id));
+                queue.tombstones = 0;
+            }
+        }
+
+        Some(job)
+    }
+}
+
+impl Queue {
+    fn forget_pri(&mut self, pri: usize) {
+        if let Some(count) = self.pris.get_mut(&pri) {
+            *count -= 1;
+            if *count == 0 {
+                self.pris.remove(&pri);
+            }
+        }
+    }
+
+    /// The best live entry, dropping any tombstones above it.
+    fn peek(&mut self, jobs: &HashMap<usize, Job>) -> Option<&Entry> {
+        while let Some(entry) = self.heap.peek() {
+            if jobs.contains_key(&entry.id) {
+                break;
+            }
+
+            self.heap.pop();
+            self.tombstones -= 1;
+        }
+
+        self.heap.peek()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn pops_best_around_tombstones() {
+        let mut queued = Queued::new();
+        for (id, queue, pri) in [
+            (0, "a", 5),
+            (1, "a", 9),
+            (2, "b", 7),
+            (3, "a", 1),
+            (4, "b", 2),
+        ] {
+            queued.push(Job::new(id, queue, pri));
+        }
+        let both = ["a".to_string(), "b".to_string()];
+
+        assert_eq!(queued.remove(1).map(|job| job.id()), Some(1));
+        assert_eq!(queued.remove(1), None);
+        assert_eq!(queued.len(), 4);
+
+        assert_eq!(queued.peek_best(&both).map(|job| job.id()), Some(2));
+        let stats = queued.stats();
+        assert_eq!((stats["a"].len, stats["a"].max_pri), (2, 5));
+        assert_eq!((stats["b"].len, stats["b"].max_pri), (2, 7));
+
+        let order = std::iter::from_fn(|| queued.pop_best(&both))
+            .map(|job| job.id())
+            .collect::<Vec<_>>();
+        assert_eq!(order, [2, 0, 4, 3]);
+        assert!(queued.is_empty());
+        assert!(queued.queues.values().all(|queue| queue.tombstones == 0));
+    }
+
+    #[test]
+    fn sweeps_buried_tombstones() {
+        let mut queued = Queued::new();
+        for id in 0..10 {
+            queued.push(Job::new(id, "a", id));
+        }
+
+        // The lowest priorities never reach the top to be dropped there
+        for id in 0..6 {
+            queued.remove(id);
+        }
+        let queue = &queued.queues["a"];
+        assert_eq!((queue.heap.len(), queue.tombstones), (4, 0));
+        assert!(queued.contains(9) && !queued.contains(0));
+    }
+
+    #[test]
+    fn ties_go_first_in_first_out() {
+        let mut queued = Queued::new();
+        for (id, pri) in [(3, 1), (0, 2), (1, 1), (2, 2), (4, 1)] {
+            queued.push(Job::new(id, "a", pri));
+        }
+
+        let order = std::iter::from_fn(|| queued.pop_best(&["a"]))
+            .map(|job| job.id())
+            .collect::<Vec<_>>();
+        assert_eq!(order, [0, 2, 1, 3, 4]);
+    }
+
+    #[test]
+    fn aging_lifts_jobs_left_waiting() {
+        let mut queued = Queued::with_aging(Duration::from_secs(1));
+        let start = queued.epoch;
+        queued.push_at(Job::new(0, "a", 1), start);
+        queued.push_at(Job::new(1, "b", 5), start + Duration::from_secs(10));
+        queued.push_at(Job::new(2, "b", 12), start + Duration::from_secs(10));
+        queued.push_at(Job::new(3, "a", 11), start + Duration::from_secs(10));
+
+        // The highest priority is still job 2's, whatever comes out first
+        let stats = queued.stats();
+        assert_eq!((stats["a"].len, stats["a"].max_pri), (2, 11));
+        assert_eq!((stats["b"].len, stats["b"].max_pri), (2, 12));
+
+        // Job 0 has been waiting ten seconds longer, which is worth more than job 1's priority
+        // and as much as job 3's, but not job 2's
+        let order = std::iter::from_fn(|| queued.pop_best(&["a", "b"]))
+            .map(|job| job.id())
+            .collect::<Vec<_>>();
+        assert_eq!(order, [2, 0, 3, 1]);
+    }
+
+    proptest::proptest! {
+        /// With aging, jobs come out by their priority plus however long they've waited, whenever
+        /// that's worked out, and oldest first among those tied.
+        #[test]
+        fn aged_jobs_pop_by_effective_priority(
+            jobs in proptest::collection::vec((0..2usize, 0..20usize, 0..60u64), 1..40),
+            removed in proptest::collection::vec(proptest::prelude::any::<bool>(), 40),
+            per in 1..10u64,
+            later in 0..1000u64,
+        ) {
+            let mut queued = Queued::with_aging(Duration::from_secs(per));
+            let start = queued.epoch;
+            for (id, (queue, pri, pushed)) in jobs.iter().enumerate() {
+                let queue = ["a", "b"][*queue];
+                queued.push_at(Job::new(id, queue, *pri), start + Duration::from_secs(*pushed));
+            }
+            for id in (0..jobs.len()).filter(|id| removed[*id]) {
+                queued.remove(id);
+            }
+
+            // Priority plus seconds waited over `per`, scaled by `per` to keep it whole
+            let now = 60 + later;
+            let mut expected = jobs
+                .iter()
+                .enumerate()
+                .filter(|(id, _)| !removed[*id])
+                .map(|(id, (_, pri, pushed))| (*pri as u64 * per + (now - pushed), id))
+                .collect::<Vec<_>>();
+            expected.sort_by_key(|(effective, id)| (std::cmp::Reverse(*effective), *id));
+            let expected = expected.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+
+            let order = std::iter::from_fn(|| queued.pop_best(&["a", "b"]))
+                .map(|job| job.id())
+                .collect::<Vec<_>>();
+            proptest::prop_assert_eq!(order, expected);
+        }
+    }
+}
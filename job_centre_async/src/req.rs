@@ -9,15 +9,55 @@ pub enum Request {
         queue: String,
         pri: usize,
         job: Value,
+        /// Seconds a client may hold the job when its `get` doesn't say
+        timeout: Option<u64>,
+        /// Seconds until the job becomes visible
+        delay: Option<u64>,
+        /// When the job becomes visible, in seconds since the Unix epoch. Not with `delay`.
+        run_at: Option<u64>,
+        /// Jobs which must all be deleted before this one becomes visible
+        #[serde(default)]
+        after: Vec<usize>,
+        /// Makes retried puts safe: while a job put with the same key is still around, or was
+        /// deleted only recently, its id is returned and no job is added
+        dedup_key: Option<String>,
     },
     Get {
         queues: Vec<String>,
         wait: Option<bool>,
+        /// Seconds the client may hold the job before it goes back to its queue
+        timeout: Option<u64>,
     },
     Delete {
         id: usize,
     },
+    /// Put a job the client holds back in its queue, or with `fail`, give up on it and cancel
+    /// every job waiting on it.
     Abort {
         id: usize,
+        #[serde(default)]
+        fail: bool,
     },
+    /// Extend the lease on a job the client holds, by `timeout` seconds or the lease it was given.
+    /// A job held without a timeout has no lease, so there's nothing to do.
+    Touch {
+        id: usize,
+        timeout: Option<u64>,
+    },
+    /// List the dead letters from a queue: jobs which ran out of attempts.
+    Inspect {
+        queue: String,
+    },
+    /// Put a dead letter back in its queue, with its attempts reset.
+    Requeue {
+        id: usize,
+    },
+    /// How full each queue is, and how many jobs each client holds.
+    Stats,
+    /// The job a `get` from `queues` would be handed, without taking it.
+    Peek {
+        queues: Vec<String>,
+    },
+    /// Every job held by a client.
+    ListInFlight,
 }
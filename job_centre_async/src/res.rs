@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::work::Job;
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
@@ -15,6 +18,9 @@ pub enum Response {
     Put {
         status: ResponseStatus,
         id: usize,
+        /// When a scheduled job becomes visible, in seconds since the Unix epoch
+        #[serde(skip_serializing_if = "Option::is_none")]
+        run_at: Option<u64>,
     },
     Get {
         status: ResponseStatus,
@@ -22,6 +28,10 @@ pub enum Response {
         queue: String,
         pri: usize,
         job: Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        run_at: Option<u64>,
+        /// Times the job has been handed out, this one included
+        attempts: usize,
     },
     Delete {
         status: ResponseStatus,
@@ -29,7 +39,48 @@ pub enum Response {
     Abort {
         status: ResponseStatus,
     },
+    Touch {
+        status: ResponseStatus,
+    },
+    Inspect {
+        status: ResponseStatus,
+        jobs: Vec<Job>,
+    },
+    Requeue {
+        status: ResponseStatus,
+    },
+    Stats {
+        status: ResponseStatus,
+        queues: BTreeMap<String, QueueStats>,
+        /// How many jobs each client holds, by client id
+        in_flight: BTreeMap<usize, usize>,
+    },
+    Peek {
+        status: ResponseStatus,
+        #[serde(flatten)]
+        job: Job,
+    },
+    InFlight {
+        status: ResponseStatus,
+        jobs: Vec<InFlightJob>,
+    },
     Err {
         status: ResponseStatus,
     },
 }
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct QueueStats {
+    pub len: usize,
+    pub max_pri: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InFlightJob {
+    pub client_id: usize,
+    /// Seconds left on the job's lease, if it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_in: Option<u64>,
+    #[serde(flatten)]
+    pub job: Job,
+}
@@ -0,0 +1,598 @@
+use crate::work::Job;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// A change to the jobs, as stored in the log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Event {
+    Put {
+        job: Job,
+    },
+    /// The job was handed to a client
+    Get {
+        id: usize,
+    },
+    Delete {
+        id: usize,
+        /// When to forget the job's dedup key, in seconds since the Unix epoch, if it had one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        forget_key_at: Option<u64>,
+    },
+    /// The job went back to its queue, whether asked to or because its client left
+    Abort {
+        id: usize,
+    },
+    /// The job came back once too often, and was set aside in its queue's dead letters
+    Dead {
+        id: usize,
+    },
+    /// The dead letter went back to its queue to be tried afresh
+    Requeue {
+        id: usize,
+    },
+    /// The job failed, and with it every job waiting on it
+    Fail {
+        id: usize,
+        /// When to forget the dedup keys of the jobs failed, in seconds since the Unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        forget_key_at: Option<u64>,
+    },
+}
+
+/// The dedup key of a deleted job, which still finds it until `forget_at`, in seconds since the
+/// Unix epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeletedKey {
+    pub key: String,
+    pub id: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run_at: Option<u64>,
+    pub forget_at: u64,
+}
+
+/// Every job at a point in time, the id the next one will get, and the log carrying on from it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Snapshot {
+    next_id: usize,
+    #[serde(default)]
+    log: u64,
+    jobs: Vec<Job>,
+    #[serde(default)]
+    dead: Vec<Job>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    deleted_keys: Vec<DeletedKey>,
+}
+
+/// The jobs rebuilt from disk, all queued: there are no clients yet for any to be in flight with.
+#[derive(Debug, Default)]
+pub struct Recovered {
+    pub next_id: usize,
+    pub jobs: Vec<Job>,
+    /// Dead letters, which stay set aside
+    pub dead: Vec<Job>,
+    /// Keys of deleted jobs which haven't been forgotten yet
+    pub deleted_keys: Vec<DeletedKey>,
+}
+
+/// Write-ahead log of [`Event`]s, one JSON object per line, on top of the last snapshot.
+#[derive(Debug)]
+pub struct Wal {
+    dir: PathBuf,
+    log: File,
+    /// Which log is being written, numbered so that each snapshot can name the one after it
+    generation: u64,
+    /// Whether each event is flushed to disk, rather than just to the OS
+    sync: bool,
+    /// Whether the next append is torn partway through
+    #[cfg(test)]
+    tear: bool,
+}
+
+impl Wal {
+    /// Open (or create) the log in `dir`, returning it with the jobs it holds.
+    ///
+    /// A crash mid-append can leave a torn final line. That event was never acknowledged, so it is
+    /// dropped and the file truncated back to the last complete one. The log the snapshot names
+    /// is replayed, then any started after it by a snapshot which never landed, so each event is
+    /// applied exactly once. Logs from before the snapshot are left from a crash just after it
+    /// landed, and removed.
+    pub fn open(dir: &Path, sync: bool) -> io::Result<(Self, Recovered)> {
+        fs::create_dir_all(dir)?;
+
+        let snapshot: Snapshot = match fs::read(dir.join("snapshot")) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(err) => return Err(err),
+        };
+
+        let mut generations = Vec::new();
+        for generation in logs(dir)? {
+            if generation < snapshot.log {
+                fs::remove_file(log_path(dir, generation))?;
+            } else {
+                generations.push(generation);
+            }
+        }
+        generations.sort_unstable();
+        let generation = generations.last().copied().unwrap_or(snapshot.log);
+
+        let mut events = Vec::new();
+        for earlier in generations.iter().filter(|g| **g != generation) {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(log_path(dir, *earlier))?;
+            events.extend(read_log(&mut file)?);
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(log_path(dir, generation))?;
+        events.extend(read_log(&mut file)?);
+        file.seek(SeekFrom::End(0))?;
+
+        let wal = Self {
+            dir: dir.to_path_buf(),
+            log: file,
+            generation,
+            sync,
+            #[cfg(test)]
+            tear: false,
+        };
+
+        Ok((wal, replay(snapshot, events)))
+    }
+
+    /// Append `event`, or leave the log as it was if it can't be.
+    pub fn append(&mut self, event: &Event) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        let len = self.log.metadata()?.len();
+
+        let res = self.write_line(&line);
+
+        if res.is_err() {
+            // Don't leave a partial line for the next append to be glued onto
+            self.log.set_len(len).ok();
+        }
+
+        res
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        #[cfg(test)]
+        if std::mem::take(&mut self.tear) {
+            self.log.write_all(&line[..line.len() / 2])?;
+            return Err(io::Error::other("torn append"));
+        }
+
+        self.log.write_all(line)?;
+
+        if self.sync {
+            self.log.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    /// Start a new log for the events after `jobs` and the `dead` letters, which must be every
+    /// job there is, and the `deleted_keys` still remembered, returning the snapshot of them to
+    /// write. Writing it can be left until later: until then the old log carries on being
+    /// replayed, followed by the new one.
+    pub fn checkpoint(
+        &mut self,
+        next_id: usize,
+        jobs: Vec<Job>,
+        dead: Vec<Job>,
+        deleted_keys: Vec<DeletedKey>,
+    ) -> io::Result<Checkpoint> {
+        if self.sync {
+            self.log.sync_data()?;
+        }
+
+        let generation = self.generation + 1;
+        let log = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .truncate(false)
+            .open(log_path(&self.dir, generation))?;
+        if self.sync {
+            File::open(&self.dir)?.sync_all()?;
+        }
+
+        self.log = log;
+        self.generation = generation;
+
+        Ok(Checkpoint {
+            dir: self.dir.clone(),
+            snapshot: Snapshot {
+                next_id,
+                log: generation,
+                jobs,
+                dead,
+                deleted_keys,
+            },
+        })
+    }
+}
+
+/// A snapshot taken by [`Wal::checkpoint`], still to be written.
+#[derive(Debug)]
+pub struct Checkpoint {
+    dir: PathBuf,
+    snapshot: Snapshot,
+}
+
+impl Checkpoint {
+    /// Replace the snapshot on disk with this one, then remove the logs it supersedes.
+    pub fn write(self) -> io::Result<()> {
+        let staged = self.dir.join("snapshot.tmp");
+        let mut file = File::create(&staged)?;
+        serde_json::to_writer(BufWriter::new(&mut file), &self.snapshot)?;
+        file.sync_all()?;
+        fs::rename(&staged, self.dir.join("snapshot"))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        for generation in logs(&self.dir)? {
+            if generation < self.snapshot.log {
+                fs::remove_file(log_path(&self.dir, generation))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The generations of the logs in `dir`.
+fn logs(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut generations = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let generation = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("log."))
+            .and_then(|generation| generation.parse::<u64>().ok());
+
+        generations.extend(generation);
+    }
+
+    Ok(generations)
+}
+
+/// Every complete event in `file`, which is truncated back to the last of them.
+fn read_log(file: &mut File) -> io::Result<Vec<Event>> {
+    let mut events = Vec::new();
+    let mut valid_len = 0;
+    let mut reader = BufReader::new(&*file);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+
+        if n == 0 || !line.ends_with('\n') {
+            break;
+        }
+
+        match serde_json::from_str::<Event>(&line) {
+            Ok(event) => events.push(event),
+            Err(err) => {
+                tracing::warn!(%err, offset = valid_len, "discarding corrupt log tail");
+                break;
+            }
+        }
+
+        valid_len += n as u64;
+    }
+
+    if valid_len != file.metadata()?.len() {
+        file.set_len(valid_len)?;
+        file.sync_all()?;
+    }
+
+    Ok(events)
+}
+
+#[cfg(test)]
+impl Wal {
+    /// Have every append fail from now on, as if the disk had gone away.
+    pub(crate) fn break_log(&mut self) {
+        self.log = File::open(log_path(&self.dir, self.generation)).unwrap();
+    }
+
+    /// Have the next append fail partway through writing its line.
+    pub(crate) fn tear_log(&mut self) {
+        self.tear = true;
+    }
+}
+
+fn log_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("log.{generation}"))
+}
+
+/// Apply `events` on top of `snapshot`. Jobs left in flight go back to their queues, the attempt
+/// still counted as with any other client which went away.
+fn replay(snapshot: Snapshot, events: Vec<Event>) -> Recovered {
+    let mut next_id = snapshot.next_id;
+    let by_id = |jobs: Vec<Job>| {
+        jobs.into_iter()
+            .map(|job| (job.id(), job))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let mut jobs = by_id(snapshot.jobs);
+    let mut dead = by_id(snapshot.dead);
+    let mut in_flight = HashSet::new();
+    let mut deleted_keys = snapshot
+        .deleted_keys
+        .into_iter()
+        .map(|deleted| (deleted.key.clone(), deleted))
+        .collect::<HashMap<_, _>>();
+
+    for event in events {
+        match event {
+            Event::Put { job } => {
+                next_id = next_id.max(job.id() + 1);
+                if let Some(key) = job.dedup_key() {
+                    deleted_keys.remove(key);
+                }
+                jobs.insert(job.id(), job);
+            }
+            Event::Get { id } => {
+                if let Some(job) = jobs.get_mut(&id) {
+                    job.attempted();
+                    in_flight.insert(id);
+                }
+            }
+            Event::Delete { id, forget_key_at } => {
+                let job = jobs.remove(&id).or(dead.remove(&id));
+                in_flight.remove(&id);
+                remember_key(&mut deleted_keys, job, forget_key_at);
+            }
+            // Which cancels the jobs waiting on it too, and those waiting on them
+            Event::Fail { id, forget_key_at } => {
+                let mut failed = vec![id];
+
+                while let Some(id) = failed.pop() {
+                    let job = jobs.remove(&id).or(dead.remove(&id));
+                    in_flight.remove(&id);
+                    remember_key(&mut deleted_keys, job, forget_key_at);
+
+                    failed.extend(
+                        jobs.values()
+                            .filter(|job| job.after().contains(&id))
+                            .map(Job::id),
+                    );
+                }
+            }
+            Event::Abort { id } => {
+                in_flight.remove(&id);
+            }
+            Event::Dead { id } => {
+                in_flight.remove(&id);
+                if let Some(job) = jobs.remove(&id) {
+                    dead.insert(id, job);
+                }
+            }
+            Event::Requeue { id } => {
+                if let Some(mut job) = dead.remove(&id) {
+                    job.revive();
+                    jobs.insert(id, job);
+                }
+            }
+        }
+    }
+
+    tracing::info!(
+        jobs = jobs.len(),
+        dead = dead.len(),
+        returned = in_flight.len(),
+        next_id,
+        "recovered jobs"
+    );
+
+    Recovered {
+        next_id,
+        jobs: jobs.into_values().collect(),
+        dead: dead.into_values().collect(),
+        deleted_keys: deleted_keys.into_values().collect(),
+    }
+}
+
+/// Remember the dedup key of `job`, deleted while replaying, until `forget_at`.
+fn remember_key(
+    deleted_keys: &mut HashMap<String, DeletedKey>,
+    job: Option<Job>,
+    forget_at: Option<u64>,
+) {
+    let Some((job, forget_at)) = job.zip(forget_at) else {
+        return;
+    };
+
+    if let Some(key) = job.dedup_key() {
+        let deleted = DeletedKey {
+            key: key.to_string(),
+            id: job.id(),
+            run_at: job.run_at(),
+            forget_at,
+        };
+        deleted_keys.insert(deleted.key.clone(), deleted);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(id: usize) -> Job {
+        Job::new(id, "q", id)
+    }
+
+    fn ids(recovered: &Recovered) -> Vec<usize> {
+        recovered.jobs.iter().map(Job::id).collect()
+    }
+
+    #[test]
+    fn replays_events_over_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut wal, recovered) = Wal::open(dir.path(), false).unwrap();
+        assert_eq!(recovered.next_id, 0);
+        for event in [
+            Event::Put { job: job(0) },
+            Event::Put { job: job(1) },
+            Event::Get { id: 0 },
+        ] {
+            wal.append(&event).unwrap();
+        }
+        wal.checkpoint(2, vec![job(0), job(1)], vec![], vec![])
+            .unwrap()
+            .write()
+            .unwrap();
+        for event in [
+            Event::Put { job: job(2) },
+            Event::Get { id: 2 },
+            Event::Delete {
+                id: 1,
+                forget_key_at: None,
+            },
+        ] {
+            wal.append(&event).unwrap();
+        }
+        drop(wal);
+
+        // Job 2 was in flight, so it's back
+        let (_, recovered) = Wal::open(dir.path(), false).unwrap();
+        assert_eq!(recovered.next_id, 3);
+        assert_eq!(ids(&recovered), [0, 2]);
+    }
+
+    #[test]
+    fn failed_append_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut wal, _) = Wal::open(dir.path(), true).unwrap();
+        wal.append(&Event::Put { job: job(0) }).unwrap();
+        wal.tear_log();
+        assert!(wal.append(&Event::Put { job: job(1) }).is_err());
+        wal.append(&Event::Put { job: job(2) }).unwrap();
+        drop(wal);
+
+        // The refused put is gone, and the one after it isn't lost behind its torn line
+        let (_, recovered) = Wal::open(dir.path(), false).unwrap();
+        let mut ids = ids(&recovered);
+        ids.sort_unstable();
+        assert_eq!(ids, [0, 2]);
+    }
+
+    #[test]
+    fn interrupted_snapshot_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut wal, _) = Wal::open(dir.path(), true).unwrap();
+        wal.append(&Event::Put { job: job(0) }).unwrap();
+        wal.append(&Event::Put { job: job(1) }).unwrap();
+        wal.append(&Event::Get { id: 0 }).unwrap();
+
+        // The next log was started, but the snapshot pointing at it never landed
+        let checkpoint = wal
+            .checkpoint(2, vec![job(0), job(1)], vec![], vec![])
+            .unwrap();
+        wal.append(&Event::Delete {
+            id: 1,
+            forget_key_at: None,
+        })
+        .unwrap();
+        drop((wal, checkpoint));
+
+        let (mut wal, recovered) = Wal::open(dir.path(), false).unwrap();
+        let mut attempted = job(0);
+        attempted.attempted();
+        assert_eq!(recovered.jobs, [attempted.clone()]);
+
+        // Which carries on from both logs until the next snapshot lands
+        wal.append(&Event::Put { job: job(2) }).unwrap();
+        let jobs = vec![attempted, job(2)];
+        let checkpoint = wal.checkpoint(3, jobs.clone(), vec![], vec![]).unwrap();
+        let (_, recovered) = Wal::open(dir.path(), false).unwrap();
+        assert_eq!(recovered.jobs, jobs);
+
+        checkpoint.write().unwrap();
+        assert_eq!(logs(dir.path()).unwrap(), [2]);
+        let (_, recovered) = Wal::open(dir.path(), false).unwrap();
+        assert_eq!(recovered.jobs, jobs);
+    }
+
+    #[test]
+    fn dead_letters_are_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut wal, _) = Wal::open(dir.path(), false).unwrap();
+        for event in [
+            Event::Put { job: job(0) },
+            Event::Put { job: job(1) },
+            Event::Get { id: 0 },
+            Event::Dead { id: 0 },
+            Event::Get { id: 1 },
+            Event::Dead { id: 1 },
+            Event::Requeue { id: 1 },
+        ] {
+            wal.append(&event).unwrap();
+        }
+        drop(wal);
+
+        let (mut wal, recovered) = Wal::open(dir.path(), false).unwrap();
+        let mut attempted = job(0);
+        attempted.attempted();
+        assert_eq!(recovered.jobs, [job(1)]);
+        assert_eq!(recovered.dead, [attempted]);
+
+        wal.checkpoint(2, recovered.jobs, recovered.dead, vec![])
+            .unwrap()
+            .write()
+            .unwrap();
+        wal.append(&Event::Delete {
+            id: 0,
+            forget_key_at: None,
+        })
+        .unwrap();
+        drop(wal);
+
+        let (_, recovered) = Wal::open(dir.path(), false).unwrap();
+        assert_eq!(ids(&recovered), [1]);
+        assert!(recovered.dead.is_empty());
+    }
+
+    #[test]
+    fn torn_tail_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let (mut wal, _) = Wal::open(dir.path(), false).unwrap();
+        wal.append(&Event::Put { job: job(0) }).unwrap();
+        drop(wal);
+
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("log.0"))
+            .unwrap();
+        log.write_all(br#"{"op":"put","job":{"id":1"#).unwrap();
+
+        let (mut wal, recovered) = Wal::open(dir.path(), false).unwrap();
+        assert_eq!(ids(&recovered), [0]);
+        wal.append(&Event::Put { job: job(1) }).unwrap();
+        drop(wal);
+
+        let (_, recovered) = Wal::open(dir.path(), false).unwrap();
+        assert_eq!(ids(&recovered), [0, 1]);
+    }
+}
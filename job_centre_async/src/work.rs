@@ -1,17 +1,100 @@
 use std::{
-    collections::{BinaryHeap, HashMap},
-    sync::atomic::AtomicUsize,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    io,
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tokio::sync::{broadcast, oneshot};
+use tokio::{
+    sync::{oneshot, Notify},
+    time::Instant,
+};
+
+use crate::{
+    queue::Queued,
+    res::{InFlightJob, QueueStats},
+    wal::{Checkpoint, DeletedKey, Event, Recovered, Wal},
+};
 
-#[derive(Eq, PartialEq, Clone, Debug)]
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct Job {
     id: usize,
     queue: String,
     job: Value,
     pri: usize,
+    /// Seconds a client may hold the job, unless it asks for something else
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<u64>,
+    /// When the job becomes visible, in seconds since the Unix epoch, if it was scheduled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    run_at: Option<u64>,
+    /// Times the job has been handed to a client
+    #[serde(default)]
+    attempts: usize,
+    /// Jobs which must be deleted before this one becomes visible, of those still around
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    after: Vec<usize>,
+    /// Another put with the same key finds this job rather than adding one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dedup_key: Option<String>,
+}
+
+impl Job {
+    /// A job with an empty body and nothing else set, as a put would add it to `queue`.
+    pub fn new(id: usize, queue: impl Into<String>, pri: usize) -> Self {
+        Self {
+            id,
+            queue: queue.into(),
+            job: Value::Object(Default::default()),
+            pri,
+            timeout: None,
+            run_at: None,
+            attempts: 0,
+            after: Vec::new(),
+            dedup_key: None,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    pub fn pri(&self) -> usize {
+        self.pri
+    }
+
+    pub fn timeout(&self) -> Option<u64> {
+        self.timeout
+    }
+
+    pub fn after(&self) -> &[usize] {
+        &self.after
+    }
+
+    pub fn run_at(&self) -> Option<u64> {
+        self.run_at
+    }
+
+    pub fn dedup_key(&self) -> Option<&str> {
+        self.dedup_key.as_deref()
+    }
+
+    /// Count another hand out.
+    pub fn attempted(&mut self) {
+        self.attempts += 1;
+    }
+
+    /// Bring a dead letter back with a clean slate.
+    pub fn revive(&mut self) {
+        self.attempts = 0;
+    }
 }
 
 impl From<Job> for crate::res::Response {
@@ -22,107 +105,818 @@ impl From<Job> for crate::res::Response {
             queue: job.queue,
             pri: job.pri,
             job: job.job,
+            run_at: job.run_at,
+            attempts: job.attempts,
         }
     }
 }
 
-impl Ord for Job {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.pri.cmp(&other.pri)
+/// A job for [`JobQueues::add`], as put by a client.
+#[derive(Debug, Clone, Default)]
+pub struct NewJob {
+    pub queue: String,
+    pub job: Value,
+    pub pri: usize,
+    /// Seconds a client may hold the job, unless it asks for something else
+    pub timeout: Option<u64>,
+    /// When the job becomes visible, in seconds since the Unix epoch
+    pub run_at: Option<u64>,
+    /// Jobs which must be deleted before this one becomes visible
+    pub after: Vec<usize>,
+    /// Another put with the same key finds this job rather than adding one
+    pub dedup_key: Option<String>,
+}
+
+/// Per-queue limits. Queues without one aren't limited.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Times a job is handed out before it's set aside as a dead letter
+    pub max_attempts: Vec<QueueLimit>,
+    /// Jobs held by clients at once
+    pub max_in_flight: Vec<QueueLimit>,
+    /// Jobs handed out per second
+    pub max_rate: Vec<QueueLimit>,
+    /// How long a queued job waits for its priority to go up by one, in every queue
+    pub aging: Option<Duration>,
+    /// How long a deleted job's dedup key still finds it
+    pub dedup_window: Duration,
+}
+
+/// A limit on one queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueLimit {
+    pub queue: String,
+    pub limit: usize,
+}
+
+impl FromStr for QueueLimit {
+    type Err = String;
+
+    /// Parse `queue:limit`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((queue, limit)) = s.rsplit_once(':') else {
+            return Err(format!("expected queue:limit, got {s}"));
+        };
+
+        match limit.parse() {
+            Ok(limit) if limit > 0 => Ok(Self {
+                queue: queue.to_string(),
+                limit,
+            }),
+            _ => Err(format!("limit must be a number above 0, got {limit}")),
+        }
     }
 }
 
-impl PartialOrd for Job {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+fn by_queue(limits: Vec<QueueLimit>) -> HashMap<String, usize> {
+    limits
+        .into_iter()
+        .map(|limit| (limit.queue, limit.limit))
+        .collect()
+}
+
+/// Dispatch allowance for a rate limited queue, topped up continuously to a second's worth.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    at: Instant,
+}
+
+impl Bucket {
+    fn new(rate: usize, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            at: now,
+        }
+    }
+
+    /// Whether there's a token to take as of `now`.
+    fn ready(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.at = self.at.max(now);
+
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self) {
+        self.tokens -= 1.0;
+    }
+
+    /// When there'll next be a token to take.
+    fn next(&self) -> Instant {
+        self.at + Duration::from_secs_f64((1.0 - self.tokens).max(0.0) / self.rate)
     }
 }
 
 pub struct JobQueues {
-    queues: HashMap<String, BinaryHeap<Job>>,
+    queued: Queued,
+    /// Jobs which aren't visible yet, by when they will be
+    scheduled: BTreeMap<(u64, usize), Job>,
+    /// When each scheduled job is due, by id
+    due: HashMap<usize, u64>,
+    /// Jobs which ran out of attempts, by id, until they're requeued or deleted
+    dead: BTreeMap<usize, Job>,
+    /// Jobs still waiting on others to be deleted, by id
+    pending: HashMap<usize, Job>,
+    /// The pending jobs waiting on each job, by its id
+    dependents: HashMap<usize, Vec<usize>>,
+    /// The id of every job not yet deleted or failed, wherever it is
+    live: HashSet<usize>,
+    /// The id and `run_at` of jobs put with a dedup key, by key, until the window after they're
+    /// deleted has passed
+    keys: HashMap<String, (usize, Option<u64>)>,
+    /// The dedup key of each live job put with one, by id
+    keyed: HashMap<usize, String>,
+    /// Keys of deleted jobs, by when to forget them in seconds since the Unix epoch
+    forgetting: BTreeSet<(u64, String)>,
+    dedup_window: Duration,
+    max_attempts: HashMap<String, usize>,
+    max_in_flight: HashMap<String, usize>,
+    /// Jobs from each queue with a `max_in_flight` held for a waiter or in flight
+    out: HashMap<String, usize>,
+    /// Each rate limited queue's allowance
+    buckets: HashMap<String, Bucket>,
+    /// Clients waiting for a job, by waiter id
+    waiters: HashMap<usize, Waiter>,
+    /// The waiters still without a job, in the order they started waiting on each queue
+    lines: HashMap<String, VecDeque<usize>>,
+    /// Which waiter each held job is held for, by job id
+    held: HashMap<usize, usize>,
+    next_waiter: usize,
     next_id: AtomicUsize,
-    broadcaster: broadcast::Sender<String>,
+    /// Poked when a job is scheduled, or held back by a rate limit from a waiting client, in case
+    /// the scheduler's next wake up is too late for it
+    scheduler: Arc<Notify>,
+    wal: Wal,
 }
 
 impl JobQueues {
-    pub fn new() -> Self {
-        Self {
-            queues: HashMap::new(),
-            next_id: AtomicUsize::new(0),
-            broadcaster: broadcast::channel::<String>(32).0,
+    /// Queues holding the jobs `wal` recovered, which every change is then logged to, within
+    /// `limits`.
+    pub fn new(wal: Wal, recovered: Recovered, limits: Limits) -> Self {
+        let now = Instant::now();
+
+        let live = recovered
+            .jobs
+            .iter()
+            .chain(&recovered.dead)
+            .map(|job| job.id)
+            .collect();
+        let keyed: HashMap<_, _> = recovered
+            .jobs
+            .iter()
+            .chain(&recovered.dead)
+            .filter_map(|job| Some((job.id, job.dedup_key.clone()?)))
+            .collect();
+        let keys = recovered
+            .jobs
+            .iter()
+            .chain(&recovered.dead)
+            .filter_map(|job| Some((job.dedup_key.clone()?, (job.id, job.run_at))))
+            .chain(
+                recovered
+                    .deleted_keys
+                    .iter()
+                    .map(|deleted| (deleted.key.clone(), (deleted.id, deleted.run_at))),
+            )
+            .collect();
+        let forgetting = recovered
+            .deleted_keys
+            .into_iter()
+            .map(|deleted| (deleted.forget_at, deleted.key))
+            .collect();
+
+        let mut queues = Self {
+            queued: limits.aging.map_or_else(Queued::new, Queued::with_aging),
+            scheduled: BTreeMap::new(),
+            due: HashMap::new(),
+            dead: recovered
+                .dead
+                .into_iter()
+                .map(|job| (job.id, job))
+                .collect(),
+            pending: HashMap::new(),
+            dependents: HashMap::new(),
+            live,
+            keys,
+            keyed,
+            forgetting,
+            dedup_window: limits.dedup_window,
+            max_attempts: by_queue(limits.max_attempts),
+            max_in_flight: by_queue(limits.max_in_flight),
+            out: HashMap::new(),
+            buckets: limits
+                .max_rate
+                .into_iter()
+                .map(|max| (max.queue, Bucket::new(max.limit, now)))
+                .collect(),
+            waiters: HashMap::new(),
+            lines: HashMap::new(),
+            held: HashMap::new(),
+            next_waiter: 0,
+            next_id: AtomicUsize::new(recovered.next_id),
+            scheduler: Arc::new(Notify::new()),
+            wal,
+        };
+
+        for job in recovered.jobs {
+            queues.park(job);
         }
+
+        queues
     }
 
-    pub fn subscriber(&self) -> broadcast::Receiver<String> {
-        self.broadcaster.subscribe()
+    /// What to wait on for scheduled jobs which may be due sooner, or rate limited jobs which may
+    /// be waited on.
+    pub fn scheduler(&self) -> Arc<Notify> {
+        Arc::clone(&self.scheduler)
     }
 
-    pub fn add(&mut self, queue: String, job: Value, pri: usize) -> usize {
+    /// Add a job, which is visible straight away unless it has a `run_at` in the future or is
+    /// `after` jobs which haven't been deleted yet. Those which don't exist count as deleted.
+    ///
+    /// If a job with the same `dedup_key` hasn't been deleted, or was within the dedup window,
+    /// nothing is added and that job's id is returned instead. Either way the job's `run_at` is
+    /// returned with its id. Nothing is added either if the job can't be logged.
+    pub fn add(&mut self, new: NewJob) -> io::Result<(usize, Option<u64>)> {
+        let NewJob {
+            queue,
+            job,
+            pri,
+            timeout,
+            run_at,
+            mut after,
+            dedup_key,
+        } = new;
+
+        if let Some(key) = &dedup_key {
+            self.forget_keys(now());
+            if let Some(added) = self.keys.get(key) {
+                return Ok(*added);
+            }
+        }
+
+        // Log only what's waited for, or a later job given a missing id would be after a restart
+        after.retain(|id| self.live.contains(id));
+
         let id = self
             .next_id
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
-        self.queues
-            .entry(queue.clone())
-            .or_insert(BinaryHeap::new())
-            .push(Job {
-                id,
-                queue: queue.clone(),
-                job,
-                pri,
+        let job = Job {
+            id,
+            queue,
+            job,
+            pri,
+            timeout,
+            run_at,
+            attempts: 0,
+            after,
+            dedup_key: dedup_key.clone(),
+        };
+        self.record(&Event::Put { job: job.clone() })?;
+
+        self.live.insert(id);
+        if let Some(key) = dedup_key {
+            self.keys.insert(key.clone(), (id, run_at));
+            self.keyed.insert(id, key);
+        }
+        self.park(job);
+
+        Ok((id, run_at))
+    }
+
+    /// Hold `job` back until the jobs it's after are deleted, or schedule it if there are none.
+    fn park(&mut self, mut job: Job) {
+        job.after.retain(|id| self.live.contains(id));
+        job.after.sort_unstable();
+        job.after.dedup();
+
+        if job.after.is_empty() {
+            return self.schedule(job);
+        }
+
+        for id in &job.after {
+            self.dependents.entry(*id).or_default().push(job.id);
+        }
+        self.pending.insert(job.id, job);
+    }
+
+    /// Job `id` is done with, so release the jobs waiting on it once they're waiting on no others.
+    /// Its delete must already be logged, along with when to forget its dedup key.
+    fn done(&mut self, id: usize, forget_key_at: u64) {
+        self.live.remove(&id);
+        self.expire_key(id, forget_key_at);
+
+        for dependent in self.dependents.remove(&id).unwrap_or_default() {
+            let ready = self.pending.get_mut(&dependent).is_some_and(|job| {
+                job.after.retain(|after| *after != id);
+                job.after.is_empty()
             });
 
-        self.broadcaster.send(queue).ok();
+            if ready {
+                let job = self.pending.remove(&dependent).unwrap();
+                self.schedule(job);
+            }
+        }
+    }
+
+    /// Job `id` failed, so cancel the jobs waiting on it, and those waiting on them in turn. Its
+    /// failure must already be logged, which the jobs cancelled with it are recovered from.
+    fn fail(&mut self, id: usize, forget_key_at: u64) {
+        let mut failed = vec![id];
+
+        while let Some(id) = failed.pop() {
+            self.live.remove(&id);
+            self.expire_key(id, forget_key_at);
+
+            for dependent in self.dependents.remove(&id).unwrap_or_default() {
+                if self.pending.remove(&dependent).is_some() {
+                    tracing::info!(id = dependent, after = id, "cancelling job");
+                    failed.push(dependent);
+                }
+            }
+        }
+    }
+
+    /// When the dedup key of a job deleted now is forgotten, in seconds since the Unix epoch.
+    fn forget_key_at(&self) -> u64 {
+        now() + self.dedup_window.as_secs()
+    }
+
+    /// Job `id` is gone, so its dedup key, if it had one, only lasts until `at`.
+    fn expire_key(&mut self, id: usize, at: u64) {
+        if let Some(key) = self.keyed.remove(&id) {
+            self.forgetting.insert((at, key));
+        }
+    }
+
+    /// Forget the keys of jobs deleted longer than the dedup window before `now`.
+    fn forget_keys(&mut self, now: u64) {
+        while self.forgetting.first().is_some_and(|(at, _)| *at <= now) {
+            let (_, key) = self.forgetting.pop_first().unwrap();
+            self.keys.remove(&key);
+        }
+    }
+
+    /// Move the scheduled jobs due by `now` into their queues, returning when the next one is.
+    pub fn release_due(&mut self, now: u64) -> Option<u64> {
+        while let Some(entry) = self.scheduled.first_entry().filter(|e| e.key().0 <= now) {
+            let job = entry.remove();
+            self.due.remove(&job.id);
+            self.push(job);
+        }
+
+        self.scheduled.keys().next().map(|(run_at, _)| *run_at)
+    }
 
-        id
+    fn schedule(&mut self, job: Job) {
+        match job.run_at.filter(|run_at| *run_at > now()) {
+            Some(run_at) => {
+                self.due.insert(job.id, run_at);
+                self.scheduled.insert((run_at, job.id), job);
+                self.scheduler.notify_one();
+            }
+            None => self.push(job),
+        }
     }
 
+    /// Put a job which came back from a client in its queue, or among the dead letters if that
+    /// was its last attempt.
     fn restore(&mut self, job: Job) {
+        self.release(&job.queue);
+
+        let exhausted = self
+            .max_attempts
+            .get(&job.queue)
+            .is_some_and(|max| job.attempts >= *max);
+
+        // Whatever isn't logged, the job still goes back to its queue when the log is replayed,
+        // so it's only its attempts being up which might be lost and the job can carry on
+        let event = match exhausted {
+            true => Event::Dead { id: job.id },
+            false => Event::Abort { id: job.id },
+        };
+        self.record(&event).ok();
+
+        if exhausted {
+            tracing::info!(id = job.id, queue = job.queue, "job out of attempts");
+            self.dead.insert(job.id, job);
+        } else {
+            self.push(job);
+        }
+    }
+
+    /// The dead letters from `queue`, oldest first.
+    pub fn inspect(&self, queue: &str) -> Vec<Job> {
+        self.dead
+            .values()
+            .filter(|job| job.queue == queue)
+            .cloned()
+            .collect()
+    }
+
+    /// How many jobs are in each queue, and the highest priority among them.
+    pub fn stats(&self) -> BTreeMap<String, QueueStats> {
+        self.queued.stats()
+    }
+
+    /// The job [`Self::next_best`] would hand out, without doing so.
+    pub fn peek(&mut self, candidates: &[String]) -> Option<Job> {
+        if self.max_in_flight.is_empty() && self.buckets.is_empty() {
+            return self.queued.peek_best(candidates).cloned();
+        }
+
+        let open = self.open_of(candidates);
+        self.queued.peek_best(&open).cloned()
+    }
+
+    /// Give dead letter `id` another go from its queue, with its attempts reset.
+    pub fn requeue(&mut self, id: usize) -> io::Result<Option<()>> {
+        if !self.dead.contains_key(&id) {
+            return Ok(None);
+        }
+        self.record(&Event::Requeue { id })?;
+
+        let mut job = self.dead.remove(&id).unwrap();
+        job.revive();
+        self.push(job);
+
+        Ok(Some(()))
+    }
+
+    /// Make `job` visible in its queue, or hold it for whoever has waited longest on it.
+    fn push(&mut self, job: Job) {
         let queue = job.queue.clone();
 
-        self.queues
-            .entry(queue.clone())
-            .or_insert(BinaryHeap::new())
-            .push(job);
+        self.queued.push(job);
+        self.wake_waiters(&queue);
+    }
+
+    /// Hand out what `queue` can to those waiting on it, and have the scheduler come back for any
+    /// its rate limit holds back.
+    fn wake_waiters(&mut self, queue: &str) {
+        self.dispatch(queue, Instant::now());
 
-        self.broadcaster.send(queue).ok();
+        if self.throttled(queue) {
+            self.scheduler.notify_one();
+        }
     }
 
-    pub fn next_best(&mut self, candidates: &[String]) -> Option<Job> {
-        self.queues
-            .iter_mut()
-            .filter(|(name, queue)| candidates.contains(name) && !queue.is_empty())
-            .max_by(|l, r| l.1.peek().unwrap().pri.cmp(&r.1.peek().unwrap().pri))
-            .and_then(|(_, queue)| queue.pop())
+    /// Whether `queue` has jobs which clients are waiting on but its rate limit holds back.
+    fn throttled(&self, queue: &str) -> bool {
+        self.buckets.contains_key(queue)
+            && self.lines.contains_key(queue)
+            && self.queued.len_of(queue) > 0
     }
 
-    pub fn del(&mut self, id: usize) -> Option<()> {
-        let target_queue = self.queues.iter().find_map(|(name, queue)| {
-            queue
-                .iter()
-                .any(|job| job.id == id)
-                .then_some(name.to_owned())
-        });
+    /// Hold the best jobs in `queue` for those waiting longest on it, for as long as its limits
+    /// allow as of `now`.
+    fn dispatch(&mut self, queue: &str, now: Instant) {
+        while let Some(&waiter) = self.lines.get(queue).and_then(|line| line.front()) {
+            if !self.open(queue, now) {
+                break;
+            }
+            let Some(job) = self.queued.pop_best(&[queue]) else {
+                break;
+            };
+
+            self.leave_lines(waiter);
+            self.take(queue);
+            self.hold(waiter, job);
+        }
+    }
+
+    /// Whether `queue`'s limits let it hand out another job as of `now`.
+    fn open(&mut self, queue: &str, now: Instant) -> bool {
+        let room = self
+            .max_in_flight
+            .get(queue)
+            .is_none_or(|max| self.out.get(queue).copied().unwrap_or_default() < *max);
+
+        room && self
+            .buckets
+            .get_mut(queue)
+            .is_none_or(|bucket| bucket.ready(now))
+    }
+
+    /// Those of `candidates` whose limits let them hand out another job now, once those waiting
+    /// on them have had what the limits allow. Otherwise a token refilled since the scheduler last
+    /// ran would go to whoever asked first, and steady polling could starve waiters.
+    fn open_of(&mut self, candidates: &[String]) -> Vec<String> {
+        let now = Instant::now();
+
+        candidates
+            .iter()
+            .filter(|queue| {
+                self.dispatch(queue, now);
+                self.open(queue, now)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Count a job handed out from `queue` against its limits.
+    fn take(&mut self, queue: &str) {
+        if self.max_in_flight.contains_key(queue) {
+            *self.out.entry(queue.to_string()).or_default() += 1;
+        }
+        if let Some(bucket) = self.buckets.get_mut(queue) {
+            bucket.take();
+        }
+    }
+
+    /// A job from `queue` is no longer out, so there may be room for a waiter to have another.
+    fn release(&mut self, queue: &str) {
+        if let Some(out) = self.out.get_mut(queue) {
+            *out -= 1;
+        }
+
+        self.wake_waiters(queue);
+    }
+
+    /// Hand out what rate limited queues now allow to those waiting on them, returning when the
+    /// next of those still holding jobs back can hand out another.
+    pub fn unthrottle(&mut self, now: Instant) -> Option<Instant> {
+        let throttled = self
+            .buckets
+            .keys()
+            .filter(|queue| self.lines.contains_key(*queue))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        throttled
+            .into_iter()
+            .filter_map(|queue| {
+                self.dispatch(&queue, now);
+                self.throttled(&queue).then(|| self.buckets[&queue].next())
+            })
+            .min()
+    }
+
+    /// Take the highest priority job from any of the `candidates` queues whose limits allow it,
+    /// for a client to work on. If that can't be logged the job is left where it was.
+    pub fn next_best(&mut self, candidates: &[String]) -> io::Result<Option<Job>> {
+        let job = if self.max_in_flight.is_empty() && self.buckets.is_empty() {
+            self.queued.pop_best(candidates)
+        } else {
+            let open = self.open_of(candidates);
+            self.queued.pop_best(&open)
+        };
+        let Some(mut job) = job else {
+            return Ok(None);
+        };
+
+        if let Err(err) = self.record(&Event::Get { id: job.id }) {
+            self.queued.push(job);
+            return Err(err);
+        }
+        self.take(&job.queue);
+        job.attempted();
+
+        Ok(Some(job))
+    }
+
+    /// Queue `client_id` up for the next job put in any of `queues`, none of which may have one it
+    /// could be handed now. The notify fires when there's something to [`Self::claim`] with the
+    /// returned waiter id.
+    pub fn wait(&mut self, client_id: usize, queues: Vec<String>) -> (usize, Arc<Notify>) {
+        let id = self.next_waiter;
+        self.next_waiter += 1;
+
+        for queue in &queues {
+            self.lines.entry(queue.clone()).or_default().push_back(id);
+
+            if self.throttled(queue) {
+                self.scheduler.notify_one();
+            }
+        }
+
+        let wake = Arc::new(Notify::new());
+        self.waiters.insert(
+            id,
+            Waiter {
+                client_id,
+                queues,
+                job: None,
+                wake: Arc::clone(&wake),
+            },
+        );
+
+        (id, wake)
+    }
 
-        match target_queue {
-            Some(name) => {
-                self.queues
-                    .get_mut(&name)
-                    .unwrap()
-                    .retain(|job| job.id != id);
-                Some(())
+    /// Take the job held for `waiter`, which is then done with. If that can't be logged the
+    /// waiter gives up, and the job goes to the next in line.
+    pub fn claim(&mut self, waiter: usize) -> io::Result<Claim> {
+        let Some(held) = self.waiters.get(&waiter) else {
+            return Ok(Claim::Cancelled);
+        };
+        let Some(id) = held.job.as_ref().map(|job| job.id) else {
+            return Ok(Claim::Waiting);
+        };
+
+        if let Err(err) = self.record(&Event::Get { id }) {
+            self.drop_waiter(waiter);
+            return Err(err);
+        }
+
+        let mut job = self.waiters.remove(&waiter).unwrap().job.unwrap();
+        self.held.remove(&job.id);
+        job.attempted();
+
+        Ok(Claim::Ready(job))
+    }
+
+    /// Stop `client_id` waiting, passing any jobs held for it on to the next in line.
+    pub fn cancel(&mut self, client_id: usize) {
+        let cancelled = self
+            .waiters
+            .iter()
+            .filter_map(|(id, waiter)| (waiter.client_id == client_id).then_some(*id))
+            .collect::<Vec<_>>();
+
+        for id in cancelled {
+            self.drop_waiter(id);
+        }
+    }
+
+    fn drop_waiter(&mut self, id: usize) {
+        self.leave_lines(id);
+        let waiter = self.waiters.remove(&id).unwrap();
+        waiter.wake.notify_one();
+
+        if let Some(job) = waiter.job {
+            self.held.remove(&job.id);
+            self.release(&job.queue);
+            self.push(job);
+        }
+    }
+
+    fn hold(&mut self, waiter: usize, job: Job) {
+        self.held.insert(job.id, waiter);
+
+        let waiter = self.waiters.get_mut(&waiter).unwrap();
+        waiter.job = Some(job);
+        waiter.wake.notify_one();
+    }
+
+    fn leave_lines(&mut self, waiter: usize) {
+        for queue in &self.waiters[&waiter].queues {
+            if let Some(line) = self.lines.get_mut(queue) {
+                line.retain(|id| *id != waiter);
+                if line.is_empty() {
+                    self.lines.remove(queue);
+                }
             }
-            _ => None,
         }
     }
+
+    /// Delete job `id` unless it's in flight, so long as that can be logged.
+    pub fn del(&mut self, id: usize) -> io::Result<Option<()>> {
+        let found = self.queued.contains(id)
+            || self.due.contains_key(&id)
+            || self.dead.contains_key(&id)
+            || self.pending.contains_key(&id)
+            || self.held.contains_key(&id);
+        if !found {
+            return Ok(None);
+        }
+        let at = self.forget_key_at();
+        self.record(&Event::Delete {
+            id,
+            forget_key_at: self.keyed.contains_key(&id).then_some(at),
+        })?;
+
+        if self.queued.remove(id).is_some() {
+            self.done(id, at);
+            return Ok(Some(()));
+        }
+
+        if let Some(run_at) = self.due.remove(&id) {
+            self.scheduled.remove(&(run_at, id));
+            self.done(id, at);
+            return Ok(Some(()));
+        }
+
+        if self.dead.remove(&id).is_some() || self.pending.remove(&id).is_some() {
+            self.done(id, at);
+            return Ok(Some(()));
+        }
+
+        let waiter = self.held.remove(&id).unwrap();
+        let job = self.waiters.get_mut(&waiter).unwrap().job.take().unwrap();
+        self.done(id, at);
+
+        // Back to the front of the line, for whatever its queues can hand out first
+        let queues = self.waiters[&waiter].queues.clone();
+        for queue in &queues {
+            self.lines
+                .entry(queue.clone())
+                .or_default()
+                .push_front(waiter);
+        }
+        self.release(&job.queue);
+        for queue in &queues {
+            self.wake_waiters(queue);
+        }
+
+        Ok(Some(()))
+    }
+
+    /// Take a snapshot of every job, queued, pending or dead here or in `in_flight`, so the log
+    /// can start afresh once it's written, which needn't be under any lock.
+    pub fn snapshot(&mut self, in_flight: &InFlightQueue) -> io::Result<Checkpoint> {
+        let next_id = self.next_id.load(std::sync::atomic::Ordering::SeqCst);
+        let jobs = self
+            .queued
+            .jobs()
+            .chain(self.scheduled.values())
+            .chain(self.pending.values())
+            .chain(
+                self.waiters
+                    .values()
+                    .filter_map(|waiter| waiter.job.as_ref()),
+            )
+            .chain(in_flight.0.values().map(|in_flight| &in_flight.job));
+
+        let jobs = jobs.cloned().collect();
+        let dead = self.dead.values().cloned().collect();
+        let deleted_keys = self
+            .forgetting
+            .iter()
+            .map(|(at, key)| {
+                let (id, run_at) = self.keys[key];
+                DeletedKey {
+                    key: key.clone(),
+                    id,
+                    run_at,
+                    forget_at: *at,
+                }
+            })
+            .collect();
+
+        self.wal.checkpoint(next_id, jobs, dead, deleted_keys)
+    }
+
+    fn record(&mut self, event: &Event) -> io::Result<()> {
+        self.wal
+            .append(event)
+            .inspect_err(|err| tracing::error!(%err, ?event, "failed to log job event"))
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// How long until `at`, in seconds since the Unix epoch.
+pub fn until(at: u64) -> Duration {
+    (UNIX_EPOCH + Duration::from_secs(at))
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+}
+
+/// A client waiting on a `get`, and the job held for it once there is one.
+struct Waiter {
+    client_id: usize,
+    queues: Vec<String>,
+    job: Option<Job>,
+    wake: Arc<Notify>,
+}
+
+/// Where a waiting `get` has got to.
+#[derive(Debug)]
+pub enum Claim {
+    Waiting,
+    Ready(Job),
+    /// The client went away, so it's not waiting any more
+    Cancelled,
 }
 
 struct InFlight {
     job: Job,
     client_id: usize,
     del_sender: oneshot::Sender<()>,
+    lease: Option<Lease>,
 }
 
+/// How long a client may hold a job before it goes back to its queue.
+struct Lease {
+    timeout: Duration,
+    deadline: Instant,
+}
+
+/// The job asked about is in flight with another client.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NotHolder;
+
+#[derive(Default)]
 pub struct InFlightQueue(HashMap<usize, InFlight>);
 
 impl InFlightQueue {
@@ -130,38 +924,160 @@ impl InFlightQueue {
         Self(HashMap::new())
     }
 
-    pub fn add(&mut self, job: Job, client_id: usize) -> oneshot::Receiver<()> {
+    /// Hand `job` to `client_id`, for `timeout` if given. The receiver resolves once the job is
+    /// no longer in flight.
+    pub fn add(
+        &mut self,
+        job: Job,
+        client_id: usize,
+        timeout: Option<Duration>,
+    ) -> oneshot::Receiver<()> {
         let (del_sender, del_receiver) = oneshot::channel::<()>();
+        let lease = timeout.map(|timeout| Lease {
+            timeout,
+            deadline: Instant::now() + timeout,
+        });
+
         self.0.insert(
             job.id,
             InFlight {
                 job,
                 del_sender,
                 client_id,
+                lease,
             },
         );
 
         del_receiver
     }
 
-    pub fn del(&mut self, id: usize) -> Option<()> {
-        self.0.remove(&id).map(|in_flight| {
-            in_flight.del_sender.send(()).ok();
+    /// How many jobs each client holds.
+    pub fn per_client(&self) -> BTreeMap<usize, usize> {
+        self.0.values().fold(BTreeMap::new(), |mut acc, in_flight| {
+            *acc.entry(in_flight.client_id).or_default() += 1;
+            acc
         })
     }
 
+    /// Every job in flight by id, with who holds it and for how much longer as of `now`.
+    pub fn list(&self, now: Instant) -> Vec<InFlightJob> {
+        let mut jobs = self
+            .0
+            .values()
+            .map(|in_flight| InFlightJob {
+                client_id: in_flight.client_id,
+                expires_in: in_flight
+                    .lease
+                    .as_ref()
+                    .map(|lease| lease.deadline.saturating_duration_since(now).as_secs()),
+                job: in_flight.job.clone(),
+            })
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|in_flight| in_flight.job.id);
+
+        jobs
+    }
+
+    /// When the lease on job `id` runs out, if it's in flight with one.
+    pub fn deadline(&self, id: usize) -> Option<Instant> {
+        self.0.get(&id)?.lease.as_ref().map(|lease| lease.deadline)
+    }
+
+    /// Extend the lease on job `id`, which `client_id` must hold, to `timeout` (or its original
+    /// timeout) from now.
+    pub fn touch(
+        &mut self,
+        id: usize,
+        client_id: usize,
+        timeout: Option<Duration>,
+    ) -> Result<Option<()>, NotHolder> {
+        let Some(in_flight) = self.0.get_mut(&id) else {
+            return Ok(None);
+        };
+
+        if in_flight.client_id != client_id {
+            return Err(NotHolder);
+        }
+
+        if let Some(lease) = &mut in_flight.lease {
+            lease.timeout = timeout.unwrap_or(lease.timeout);
+            lease.deadline = Instant::now() + lease.timeout;
+        }
+
+        Ok(Some(()))
+    }
+
+    /// Put job `id` back in its queue if its lease had run out by `now`, returning whether it did.
+    pub fn expire(&mut self, queues: &mut JobQueues, id: usize, now: Instant) -> bool {
+        if self.deadline(id).is_none_or(|deadline| deadline > now) {
+            return false;
+        }
+
+        let in_flight = self.0.remove(&id).unwrap();
+        queues.restore(in_flight.job);
+
+        true
+    }
+
+    /// Delete job `id`, so long as that can be logged.
+    pub fn del(&mut self, queues: &mut JobQueues, id: usize) -> io::Result<Option<()>> {
+        if !self.0.contains_key(&id) {
+            return Ok(None);
+        }
+        let at = queues.forget_key_at();
+        queues.record(&Event::Delete {
+            id,
+            forget_key_at: queues.keyed.contains_key(&id).then_some(at),
+        })?;
+
+        let in_flight = self.0.remove(&id).unwrap();
+        queues.done(id, at);
+        queues.release(&in_flight.job.queue);
+        in_flight.del_sender.send(()).ok();
+
+        Ok(Some(()))
+    }
+
+    /// Give up on job `job_id`, which `client_id` must hold, for good, and on every job waiting on
+    /// it to be deleted. Nothing changes if that can't be logged.
+    pub fn fail(
+        &mut self,
+        queues: &mut JobQueues,
+        job_id: usize,
+        client_id: usize,
+    ) -> io::Result<Result<Option<()>, NotHolder>> {
+        match self.0.get(&job_id) {
+            None => Ok(Ok(None)),
+            Some(in_flight) if in_flight.client_id != client_id => Ok(Err(NotHolder)),
+            Some(_) => {
+                let at = queues.forget_key_at();
+                queues.record(&Event::Fail {
+                    id: job_id,
+                    forget_key_at: Some(at),
+                })?;
+
+                let in_flight = self.0.remove(&job_id).unwrap();
+                queues.fail(job_id, at);
+                queues.release(&in_flight.job.queue);
+                in_flight.del_sender.send(()).ok();
+
+                Ok(Ok(Some(())))
+            }
+        }
+    }
+
     pub fn abort(
         &mut self,
         queues: &mut JobQueues,
         job_id: usize,
         client_id: usize,
-    ) -> Result<Option<()>, ()> {
+    ) -> Result<Option<()>, NotHolder> {
         if self
             .0
             .get(&job_id)
             .is_some_and(|in_flight| in_flight.client_id != client_id)
         {
-            return Err(());
+            return Err(NotHolder);
         }
 
         if let Some(in_flight) = self.0.remove(&job_id) {
@@ -185,3 +1101,587 @@ impl InFlightQueue {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn put(queue: &str, pri: usize) -> NewJob {
+        NewJob {
+            queue: queue.into(),
+            pri,
+            ..NewJob::default()
+        }
+    }
+
+    fn queues(dir: &tempfile::TempDir) -> JobQueues {
+        let (wal, recovered) = Wal::open(dir.path(), false).unwrap();
+        let limits = Limits {
+            max_attempts: vec!["limited:2".parse().unwrap()],
+            max_in_flight: vec!["capped:1".parse().unwrap()],
+            max_rate: vec!["slow:2".parse().unwrap()],
+            aging: None,
+            dedup_window: Duration::from_secs(60),
+        };
+        JobQueues::new(wal, recovered, limits)
+    }
+
+    #[test]
+    fn leases_expire_unless_touched() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+        let q = ["q".to_string()];
+
+        queues.add(put("q", 1)).unwrap();
+        let job = queues.next_best(&q).unwrap().unwrap();
+        let start = Instant::now();
+        in_flight.add(job, 7, Some(Duration::from_secs(10)));
+        assert!(in_flight.deadline(0).is_some_and(|d| d >= start));
+
+        assert_eq!(in_flight.touch(0, 8, None), Err(NotHolder));
+        assert_eq!(in_flight.touch(1, 7, None), Ok(None));
+        assert_eq!(
+            in_flight.touch(0, 7, Some(Duration::from_secs(60))),
+            Ok(Some(()))
+        );
+
+        let later = start + Duration::from_secs(30);
+        assert!(!in_flight.expire(&mut queues, 0, later));
+        assert!(queues.next_best(&q).unwrap().is_none());
+
+        let much_later = start + Duration::from_secs(120);
+        assert!(in_flight.expire(&mut queues, 0, much_later));
+        assert!(in_flight.deadline(0).is_none());
+        assert_eq!(queues.next_best(&q).unwrap().map(|job| job.id()), Some(0));
+    }
+
+    #[test]
+    fn unleased_jobs_never_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+
+        queues
+            .add(NewJob {
+                timeout: Some(5),
+                ..put("q", 1)
+            })
+            .unwrap();
+        let job = queues.next_best(&["q".to_string()]).unwrap().unwrap();
+        assert_eq!(job.timeout(), Some(5));
+        in_flight.add(job, 7, None);
+
+        let much_later = Instant::now() + Duration::from_secs(3600);
+        assert_eq!(
+            in_flight.touch(0, 7, Some(Duration::from_secs(1))),
+            Ok(Some(()))
+        );
+        assert!(!in_flight.expire(&mut queues, 0, much_later));
+    }
+
+    #[test]
+    fn scheduled_jobs_wait_until_due() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let q = ["q".to_string()];
+        let at = now() + 100;
+
+        queues
+            .add(NewJob {
+                run_at: Some(at),
+                ..put("q", 1)
+            })
+            .unwrap();
+        queues
+            .add(NewJob {
+                run_at: Some(at + 1),
+                ..put("q", 1)
+            })
+            .unwrap();
+        queues
+            .add(NewJob {
+                run_at: Some(now() - 1),
+                ..put("q", 1)
+            })
+            .unwrap();
+        assert_eq!(queues.next_best(&q).unwrap().map(|job| job.id()), Some(2));
+        assert!(queues.next_best(&q).unwrap().is_none());
+
+        assert_eq!(queues.release_due(now()), Some(at));
+        assert_eq!(queues.del(1).unwrap(), Some(()));
+        assert_eq!(queues.release_due(at), None);
+        let job = queues.next_best(&q).unwrap().unwrap();
+        assert_eq!((job.id, job.run_at), (0, Some(at)));
+        drop(queues);
+
+        // Still scheduled after a restart
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, _) = Wal::open(dir.path(), false).unwrap();
+        wal.append(&Event::Put {
+            job: Job {
+                run_at: Some(at),
+                ..Job::new(0, "q", 1)
+            },
+        })
+        .unwrap();
+        drop(wal);
+
+        let mut queues = self::queues(&dir);
+        assert!(queues.next_best(&q).unwrap().is_none());
+        assert_eq!(queues.release_due(at), None);
+        assert!(queues.next_best(&q).unwrap().is_some());
+    }
+
+    #[test]
+    fn exhausted_jobs_become_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+        let limited = ["limited".to_string()];
+
+        queues.add(put("limited", 1)).unwrap();
+        queues.add(put("other", 1)).unwrap();
+        for client_id in 0..2 {
+            let job = queues.next_best(&limited).unwrap().unwrap();
+            assert_eq!(job.attempts, client_id + 1);
+            in_flight.add(job, client_id, None);
+            in_flight.cleanup(&mut queues, client_id);
+        }
+        assert!(queues.next_best(&limited).unwrap().is_none());
+        assert_eq!(
+            queues
+                .inspect("limited")
+                .iter()
+                .map(Job::id)
+                .collect::<Vec<_>>(),
+            [0]
+        );
+        assert!(queues.inspect("other").is_empty());
+
+        // Queues without a limit keep going
+        for client_id in 0..5 {
+            let job = queues.next_best(&["other".to_string()]).unwrap().unwrap();
+            in_flight.add(job, client_id, None);
+            assert_eq!(in_flight.abort(&mut queues, 1, client_id), Ok(Some(())));
+        }
+
+        assert_eq!(queues.requeue(1).unwrap(), None);
+        assert_eq!(queues.requeue(0).unwrap(), Some(()));
+        assert_eq!(queues.requeue(0).unwrap(), None);
+        assert_eq!(
+            queues.next_best(&limited).unwrap().map(|job| job.attempts),
+            Some(1)
+        );
+        drop(queues);
+
+        // Requeued and put back after the restart, then dead again for good
+        let mut queues = self::queues(&dir);
+        let job = queues.next_best(&limited).unwrap().unwrap();
+        assert_eq!(job.attempts, 2);
+        queues.restore(job);
+        assert_eq!(queues.inspect("limited").len(), 1);
+        assert_eq!(queues.del(0).unwrap(), Some(()));
+        assert!(queues.inspect("limited").is_empty());
+    }
+
+    #[test]
+    fn waiters_get_one_job_each_in_turn() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let [q, r] = ["q", "r"].map(String::from);
+
+        let (first, _) = queues.wait(1, vec![q.clone()]);
+        let (second, _) = queues.wait(2, vec![q.clone(), r.clone()]);
+        let (third, _) = queues.wait(3, vec![r.clone()]);
+        assert!(matches!(queues.claim(first).unwrap(), Claim::Waiting));
+
+        queues.add(put(&r, 1)).unwrap();
+        queues.add(put(&q, 1)).unwrap();
+        queues.add(put(&q, 1)).unwrap();
+        assert!(matches!(queues.claim(second).unwrap(), Claim::Ready(job) if job.id == 0));
+        assert!(matches!(queues.claim(first).unwrap(), Claim::Ready(job) if job.id == 1));
+        assert!(matches!(queues.claim(third).unwrap(), Claim::Waiting));
+
+        // The second waiter is done, so job 2 waits for a get
+        assert!(matches!(queues.claim(second).unwrap(), Claim::Cancelled));
+        assert_eq!(queues.next_best(&[q]).unwrap().map(|job| job.id), Some(2));
+
+        queues.add(put(&r, 1)).unwrap();
+        assert!(
+            matches!(queues.claim(third).unwrap(), Claim::Ready(job) if job.id == 3 && job.attempts == 1)
+        );
+    }
+
+    #[test]
+    fn cancelled_waiters_pass_jobs_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let q = "q".to_string();
+
+        let (first, _) = queues.wait(1, vec![q.clone()]);
+        let (second, _) = queues.wait(2, vec![q.clone()]);
+        let (third, _) = queues.wait(3, vec![q.clone()]);
+        queues.add(put(&q, 1)).unwrap();
+        queues.cancel(1);
+        assert!(matches!(queues.claim(first).unwrap(), Claim::Cancelled));
+
+        // Deleting the held job puts its waiter back at the front
+        assert_eq!(queues.del(0).unwrap(), Some(()));
+        assert!(matches!(queues.claim(second).unwrap(), Claim::Waiting));
+        queues.add(put(&q, 1)).unwrap();
+        assert!(matches!(queues.claim(third).unwrap(), Claim::Waiting));
+        assert!(matches!(queues.claim(second).unwrap(), Claim::Ready(job) if job.id == 1));
+    }
+
+    #[test]
+    fn in_flight_by_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+        let q = ["q".to_string()];
+
+        for _ in 0..3 {
+            queues.add(put("q", 1)).unwrap();
+        }
+        let now = Instant::now();
+        in_flight.add(queues.next_best(&q).unwrap().unwrap(), 7, None);
+        in_flight.add(
+            queues.next_best(&q).unwrap().unwrap(),
+            8,
+            Some(Duration::from_secs(30)),
+        );
+        in_flight.add(queues.next_best(&q).unwrap().unwrap(), 7, None);
+
+        assert_eq!(in_flight.per_client(), BTreeMap::from([(7, 2), (8, 1)]));
+        let listed = in_flight
+            .list(now)
+            .into_iter()
+            .map(|job| (job.job.id, job.client_id, job.expires_in))
+            .collect::<Vec<_>>();
+        assert_eq!(listed, [(0, 7, None), (1, 8, Some(30)), (2, 7, None)]);
+    }
+
+    #[test]
+    fn jobs_wait_for_those_they_are_after() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+        let [a, b] = ["a", "b"].map(|q| [q.to_string()]);
+
+        queues.add(put("a", 1)).unwrap();
+        queues.add(put("a", 1)).unwrap();
+        // Job 9 doesn't exist, so there's nothing to wait for
+        queues
+            .add(NewJob {
+                after: vec![0, 1, 9],
+                ..put("b", 1)
+            })
+            .unwrap();
+        assert!(queues.next_best(&b).unwrap().is_none());
+        assert_eq!(queues.del(0).unwrap(), Some(()));
+        drop(queues);
+
+        // Still waiting on job 1 after a restart
+        let mut queues = self::queues(&dir);
+        in_flight.add(queues.next_best(&a).unwrap().unwrap(), 7, None);
+        assert!(queues.next_best(&b).unwrap().is_none());
+        assert_eq!(in_flight.abort(&mut queues, 1, 7), Ok(Some(())));
+        in_flight.add(queues.next_best(&a).unwrap().unwrap(), 7, None);
+        assert!(queues.next_best(&b).unwrap().is_none());
+
+        assert_eq!(in_flight.del(&mut queues, 1).unwrap(), Some(()));
+        assert_eq!(queues.next_best(&b).unwrap().map(|job| job.id), Some(2));
+    }
+
+    #[test]
+    fn missing_jobs_stay_missing_after_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let b = ["b".to_string()];
+
+        // Job 2 doesn't exist yet, so the job after it is ready straight away
+        queues
+            .add(NewJob {
+                after: vec![2],
+                ..put("b", 1)
+            })
+            .unwrap();
+        queues.add(put("a", 1)).unwrap();
+        queues.add(put("a", 1)).unwrap();
+        drop(queues);
+
+        // And isn't waiting on the job which took its id since
+        let mut queues = self::queues(&dir);
+        assert_eq!(queues.next_best(&b).unwrap().map(|job| job.id), Some(0));
+    }
+
+    #[test]
+    fn failing_cancels_dependents() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+        let q = ["q".to_string()];
+
+        queues.add(put("q", 2)).unwrap();
+        queues.add(put("q", 1)).unwrap();
+        queues
+            .add(NewJob {
+                after: vec![0],
+                ..put("q", 1)
+            })
+            .unwrap();
+        queues
+            .add(NewJob {
+                after: vec![2],
+                ..put("q", 1)
+            })
+            .unwrap();
+        queues
+            .add(NewJob {
+                after: vec![1, 2],
+                ..put("q", 1)
+            })
+            .unwrap();
+        queues
+            .add(NewJob {
+                after: vec![1],
+                ..put("q", 1)
+            })
+            .unwrap();
+
+        in_flight.add(queues.next_best(&q).unwrap().unwrap(), 7, None);
+        assert_eq!(in_flight.fail(&mut queues, 0, 8).unwrap(), Err(NotHolder));
+        assert_eq!(in_flight.fail(&mut queues, 0, 7).unwrap(), Ok(Some(())));
+        assert_eq!(in_flight.fail(&mut queues, 0, 7).unwrap(), Ok(None));
+        for id in [2, 3, 4] {
+            assert_eq!(queues.del(id).unwrap(), None);
+        }
+        drop(queues);
+
+        // Job 5 was only after job 1, so it's still waiting on it after a restart
+        let mut queues = self::queues(&dir);
+        assert_eq!(queues.live, HashSet::from([1, 5]));
+        assert_eq!(queues.next_best(&q).unwrap().map(|job| job.id), Some(1));
+        assert!(queues.next_best(&q).unwrap().is_none());
+    }
+
+    #[test]
+    fn in_flight_caps_hold_jobs_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+        let capped = ["capped".to_string()];
+
+        for _ in 0..3 {
+            queues.add(put("capped", 1)).unwrap();
+        }
+        let job = queues.next_best(&capped).unwrap().unwrap();
+        assert!(queues.peek(&capped).is_none());
+        assert!(queues.next_best(&capped).unwrap().is_none());
+        in_flight.add(job, 7, None);
+
+        // The waiter's turn comes once there's room, which a delete makes
+        let (waiter, _) = queues.wait(8, capped.to_vec());
+        assert!(matches!(queues.claim(waiter).unwrap(), Claim::Waiting));
+        assert_eq!(in_flight.del(&mut queues, 0).unwrap(), Some(()));
+        let Claim::Ready(job) = queues.claim(waiter).unwrap() else {
+            panic!("no job after a delete");
+        };
+        assert!(queues.next_best(&capped).unwrap().is_none());
+
+        // And so does an abort
+        in_flight.add(job, 8, None);
+        assert_eq!(in_flight.abort(&mut queues, 1, 8), Ok(Some(())));
+        assert!(queues.next_best(&capped).unwrap().is_some());
+    }
+
+    #[test]
+    fn rate_limits_pace_hand_outs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let slow = ["slow".to_string()];
+
+        for _ in 0..4 {
+            queues.add(put("slow", 1)).unwrap();
+        }
+        assert!(queues.next_best(&slow).unwrap().is_some());
+        assert!(queues.next_best(&slow).unwrap().is_some());
+        assert!(queues.next_best(&slow).unwrap().is_none());
+
+        let now = Instant::now();
+        let (waiter, _) = queues.wait(7, slow.to_vec());
+        let next = queues.unthrottle(now).unwrap();
+        assert!(next > now && next <= now + Duration::from_millis(500));
+        assert!(matches!(queues.claim(waiter).unwrap(), Claim::Waiting));
+
+        assert_eq!(queues.unthrottle(now + Duration::from_secs(1)), None);
+        assert!(matches!(queues.claim(waiter).unwrap(), Claim::Ready(_)));
+        assert!(queues.next_best(&slow).unwrap().is_some());
+        assert!(queues.next_best(&slow).unwrap().is_none());
+
+        // A token refilled before the scheduler gets round to it still goes to the waiter
+        queues.add(put("slow", 1)).unwrap();
+        let (waiter, _) = queues.wait(8, slow.to_vec());
+        queues.buckets.get_mut("slow").unwrap().tokens = 1.0;
+        assert!(queues.next_best(&slow).unwrap().is_none());
+        assert!(matches!(queues.claim(waiter).unwrap(), Claim::Ready(_)));
+    }
+
+    #[test]
+    fn unlogged_changes_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+        let q = ["q".to_string()];
+
+        for _ in 0..2 {
+            queues.add(put("q", 1)).unwrap();
+        }
+        in_flight.add(queues.next_best(&q).unwrap().unwrap(), 7, None);
+        queues.wal.break_log();
+
+        assert!(queues.add(put("q", 1)).is_err());
+        assert!(queues.next_best(&q).is_err());
+        assert!(queues.del(1).is_err());
+        assert_eq!(queues.queued.len(), 1);
+        assert!(queues.queued.contains(1));
+
+        assert!(in_flight.del(&mut queues, 0).is_err());
+        assert!(in_flight.fail(&mut queues, 0, 7).is_err());
+        assert_eq!(in_flight.per_client()[&7], 1);
+    }
+
+    #[test]
+    fn deduped_puts_keep_the_first_schedule() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let at = now() + 100;
+        let keyed = |queues: &mut JobQueues, run_at: u64| {
+            queues
+                .add(NewJob {
+                    run_at: Some(run_at),
+                    dedup_key: Some("a".into()),
+                    ..put("q", 1)
+                })
+                .unwrap()
+        };
+
+        assert_eq!(keyed(&mut queues, at), (0, Some(at)));
+        assert_eq!(keyed(&mut queues, at + 50), (0, Some(at)));
+
+        // Whether the job's been deleted, recovered, or both
+        assert_eq!(queues.del(0).unwrap(), Some(()));
+        assert_eq!(keyed(&mut queues, at + 50), (0, Some(at)));
+        drop(queues);
+        let mut queues = self::queues(&dir);
+        assert_eq!(keyed(&mut queues, at + 50), (0, Some(at)));
+    }
+
+    #[test]
+    fn dedup_keys_outlast_their_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut queues = queues(&dir);
+        let mut in_flight = InFlightQueue::new();
+        let q = ["q".to_string()];
+        let keyed = |queues: &mut JobQueues, key: &str| {
+            queues
+                .add(NewJob {
+                    dedup_key: Some(key.into()),
+                    ..put("q", 1)
+                })
+                .unwrap()
+                .0
+        };
+
+        assert_eq!(keyed(&mut queues, "a"), 0);
+        assert_eq!(keyed(&mut queues, "a"), 0);
+        assert_eq!(keyed(&mut queues, "b"), 1);
+        assert_eq!(queues.queued.len(), 2);
+
+        // In flight, then deleted but within the window
+        in_flight.add(queues.next_best(&q).unwrap().unwrap(), 7, None);
+        assert_eq!(keyed(&mut queues, "a"), 0);
+        assert_eq!(in_flight.del(&mut queues, 0).unwrap(), Some(()));
+        assert_eq!(keyed(&mut queues, "a"), 0);
+        assert!(queues
+            .next_best(&q)
+            .unwrap()
+            .is_some_and(|job| job.id() == 1));
+        assert!(queues.next_best(&q).unwrap().is_none());
+
+        // Keys are kept with their jobs, and deleted ones are logged, so they're all recovered
+        drop(queues);
+        let mut queues = self::queues(&dir);
+        assert_eq!(keyed(&mut queues, "a"), 0);
+        assert_eq!(keyed(&mut queues, "b"), 1);
+
+        // As they are from a snapshot
+        queues.snapshot(&in_flight).unwrap().write().unwrap();
+        drop(queues);
+        let mut queues = self::queues(&dir);
+        assert_eq!(keyed(&mut queues, "a"), 0);
+        assert_eq!(keyed(&mut queues, "b"), 1);
+
+        queues.forget_keys(now() + 61);
+        assert_eq!(keyed(&mut queues, "a"), 2);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Put { queue: usize, pri: usize },
+        Delete(usize),
+        Get(Vec<bool>),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (0..3usize, 0..4usize).prop_map(|(queue, pri)| Op::Put { queue, pri }),
+            1 => any::<usize>().prop_map(Op::Delete),
+            2 => proptest::collection::vec(any::<bool>(), 3).prop_map(Op::Get),
+        ]
+    }
+
+    proptest! {
+        /// Whatever's been put and deleted, a get takes the highest priority job from the queues
+        /// asked for, and the first put of those tied.
+        #[test]
+        fn next_best_takes_highest_priority_then_oldest(ops in proptest::collection::vec(op(), 1..60)) {
+            let dir = tempfile::tempdir().unwrap();
+            let mut queues = queues(&dir);
+            let names = ["a", "b", "c"];
+            let mut model: Vec<(usize, usize, usize)> = Vec::new();
+
+            for op in ops {
+                match op {
+                    Op::Put { queue, pri } => {
+                        let (id, _) = queues.add(put(names[queue], pri)).unwrap();
+                        model.push((id, queue, pri));
+                    }
+                    Op::Delete(pick) if !model.is_empty() => {
+                        let (id, ..) = model.remove(pick % model.len());
+                        prop_assert_eq!(queues.del(id).unwrap(), Some(()));
+                    }
+                    Op::Delete(_) => {}
+                    Op::Get(asked) => {
+                        let candidates = names
+                            .iter()
+                            .zip(&asked)
+                            .filter(|(_, asked)| **asked)
+                            .map(|(name, _)| name.to_string())
+                            .collect::<Vec<_>>();
+                        let expected = model
+                            .iter()
+                            .filter(|(_, queue, _)| asked[*queue])
+                            .max_by_key(|(id, _, pri)| (*pri, std::cmp::Reverse(*id)))
+                            .map(|(id, ..)| *id);
+                        model.retain(|(id, ..)| Some(*id) != expected);
+
+                        prop_assert_eq!(queues.next_best(&candidates).unwrap().map(|job| job.id()), expected);
+                    }
+                }
+            }
+        }
+    }
+}
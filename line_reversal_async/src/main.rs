@@ -27,8 +27,8 @@ pub enum MsgQueueCommands {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let socket = Arc::new(UdpSocket::bind("0.0.0.0:8080").await?);
-    eprintln!("started");
+    let config = protohackers_common::init();
+    let socket = Arc::new(protohackers_common::bind_udp(&config).await?);
 
     let (udp_sender, udp_receiver) = unbounded_channel();
     tokio::spawn(handle_send_udp(Arc::clone(&socket), udp_receiver));
@@ -43,28 +43,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         msg_q_out_sender,
     ));
 
+    let shutdown = protohackers_common::shutdown_signal();
+    tokio::pin!(shutdown);
+
     let mut buf = vec![0x00; 1000];
     loop {
-        let (amt, src) = socket.recv_from(&mut buf).await?;
+        let (amt, src) = tokio::select! {
+            _ = &mut shutdown => break,
+            res = socket.recv_from(&mut buf) => res?,
+        };
 
         match Message::try_from(&buf[..amt]) {
             Ok(msg) => {
-                eprintln!(
-                    "received {} from {}",
-                    String::from_utf8_lossy(&buf[..amt]),
-                    src
-                );
+                tracing::debug!(%src, msg = %String::from_utf8_lossy(&buf[..amt]), "received");
 
                 msg_in_sender.send((src, msg)).await.unwrap();
             }
             Err(err) => {
-                eprintln!(
-                    "coundn't get a message from {} ({err})",
-                    String::from_utf8_lossy(&buf[..amt]),
+                tracing::warn!(
+                    %err,
+                    msg = %String::from_utf8_lossy(&buf[..amt]),
+                    "couldn't get a message"
                 );
             }
         }
     }
+
+    Ok(())
 }
 
 async fn handle_send_udp(socket: Arc<UdpSocket>, mut udp_receiver: UnboundedReceiver<MsgPayload>) {
@@ -73,9 +78,9 @@ async fn handle_send_udp(socket: Arc<UdpSocket>, mut udp_receiver: UnboundedRece
 
         match socket.send_to(msg_string.as_bytes(), src).await {
             Ok(_) => {
-                eprintln!("sent {msg_string} to {src}");
+                tracing::debug!(%src, msg = msg_string, "sent");
             }
-            Err(err) => eprintln!("udp send failed, retrying ({err}"),
+            Err(err) => tracing::warn!(%err, %src, "udp send failed, retrying"),
         }
     }
 }
@@ -1,52 +1,46 @@
 use std::{
     io::{prelude::*, BufReader},
-    net::TcpListener,
-    thread,
+    net::TcpStream,
 };
 
-fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").expect("bound port 80 on 0.0.0.0");
-
-    for stream in listener.incoming() {
-        thread::spawn(move || {
-            eprintln!("Opening connection");
-
-            let mut db = Vec::new();
-
-            let mut stream = stream.expect("opened TCP stream");
-            let mut reader = BufReader::new(stream.try_clone().expect("cloned stream"));
-            let mut buf = [0x00; 9];
-
-            while reader.read_exact(&mut buf).is_ok() {
-                match buf[0] {
-                    b'I' => db.push(Insert::from(&buf)),
-                    b'Q' => {
-                        let q = Query::from(&buf);
-
-                        let matches = db
-                            .iter()
-                            .filter_map(|i| match (q.mintime..=q.maxtime).contains(&i.timestamp) {
-                                true => Some(i64::from(i.price)),
-                                false => None,
-                            })
-                            .collect::<Vec<_>>();
-
-                        let mean = i32::try_from(
-                            matches.iter().sum::<i64>() / std::cmp::max(1, matches.len()) as i64,
-                        )
-                        .expect("mean val fits into an i32");
-
-                        stream.write(&mean.to_be_bytes()).ok();
-                    }
-                    _ => {
-                        eprintln!("Skipping {buf:?}");
-                        return;
-                    }
-                }
-            }
+fn main() -> std::io::Result<()> {
+    let config = protohackers_common::init();
+
+    protohackers_common::serve_threaded(&config, handle_client)
+}
+
+fn handle_client(mut stream: TcpStream) {
+    let mut db = Vec::new();
+
+    let mut reader = BufReader::new(stream.try_clone().expect("cloned stream"));
+    let mut buf = [0x00; 9];
 
-            eprintln!("Closing connection");
-        });
+    while reader.read_exact(&mut buf).is_ok() {
+        match buf[0] {
+            b'I' => db.push(Insert::from(&buf)),
+            b'Q' => {
+                let q = Query::from(&buf);
+
+                let matches = db
+                    .iter()
+                    .filter_map(|i| match (q.mintime..=q.maxtime).contains(&i.timestamp) {
+                        true => Some(i64::from(i.price)),
+                        false => None,
+                    })
+                    .collect::<Vec<_>>();
+
+                let mean = i32::try_from(
+                    matches.iter().sum::<i64>() / std::cmp::max(1, matches.len()) as i64,
+                )
+                .expect("mean val fits into an i32");
+
+                stream.write_all(&mean.to_be_bytes()).ok();
+            }
+            _ => {
+                tracing::warn!(msg = ?buf, "skipping");
+                return;
+            }
+        }
     }
 }
 
@@ -1,41 +1,39 @@
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::task;
+use tokio::net::TcpStream;
 
 #[tokio::main]
-async fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
-
-    loop {
-        let mut conn = Connection::new(listener.accept().await.unwrap().0);
-
-        task::spawn(async move {
-            let mut db = Vec::new();
-
-            while let Some(msg) = conn.next_msg().await {
-                match msg.0 {
-                    MsgType::I => db.push(msg),
-                    MsgType::Q => {
-                        let matches = db
-                            .iter()
-                            .filter_map(|i| match (msg.1..=msg.2).contains(&i.1) {
-                                true => Some(i64::from(i.2)),
-                                false => None,
-                            })
-                            .collect::<Vec<_>>();
-
-                        let mean = i32::try_from(
-                            matches.iter().sum::<i64>() / std::cmp::max(1, matches.len()) as i64,
-                        )
-                        .expect("mean val fits into an i32");
-
-                        conn.1.write(&mean.to_be_bytes()).await.ok();
-                        conn.1.flush().await.ok();
-                    }
-                }
+async fn main() -> std::io::Result<()> {
+    let config = protohackers_common::init();
+
+    protohackers_common::serve(&config, handle_client).await
+}
+
+async fn handle_client(tcp_stream: TcpStream) {
+    let mut conn = Connection::new(tcp_stream);
+    let mut db = Vec::new();
+
+    while let Some(msg) = conn.next_msg().await {
+        match msg.0 {
+            MsgType::I => db.push(msg),
+            MsgType::Q => {
+                let matches = db
+                    .iter()
+                    .filter_map(|i| match (msg.1..=msg.2).contains(&i.1) {
+                        true => Some(i64::from(i.2)),
+                        false => None,
+                    })
+                    .collect::<Vec<_>>();
+
+                let mean = i32::try_from(
+                    matches.iter().sum::<i64>() / std::cmp::max(1, matches.len()) as i64,
+                )
+                .expect("mean val fits into an i32");
+
+                conn.1.write_all(&mean.to_be_bytes()).await.ok();
+                conn.1.flush().await.ok();
             }
-        });
+        }
     }
 }
 
@@ -0,0 +1,76 @@
+use clap::Parser;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+/// Listener settings shared by every server, taken from CLI flags or `PH_*` env vars.
+#[derive(Parser, Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Address to listen on
+    #[arg(long, env = "PH_ADDR", default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    pub addr: IpAddr,
+
+    /// Port to listen on
+    #[arg(long, short, env = "PH_PORT", default_value_t = 8080)]
+    pub port: u16,
+
+    /// Maximum number of connections served at once
+    #[arg(long, env = "PH_MAX_CONNECTIONS", default_value_t = 1024)]
+    pub max_connections: usize,
+
+    /// Seconds to let open connections finish after SIGINT/SIGTERM
+    #[arg(long, env = "PH_DRAIN_TIMEOUT", default_value_t = 10)]
+    pub drain_timeout: u64,
+}
+
+impl Config {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr, self.port)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8080,
+            max_connections: 1024,
+            drain_timeout: 10,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let config = Config::try_parse_from(["server"]).unwrap();
+
+        assert_eq!(config, Config::default());
+        assert_eq!(config.socket_addr(), "0.0.0.0:8080".parse().unwrap());
+    }
+
+    #[test]
+    fn flags() {
+        let config = Config::try_parse_from([
+            "server",
+            "--addr",
+            "127.0.0.1",
+            "--port",
+            "9000",
+            "--max-connections",
+            "5",
+        ])
+        .unwrap();
+
+        assert_eq!(config.socket_addr(), "127.0.0.1:9000".parse().unwrap());
+        assert_eq!(config.max_connections, 5);
+    }
+}
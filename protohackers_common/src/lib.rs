@@ -0,0 +1,35 @@
+//! Server plumbing shared by the protohackers binaries: listener config, connection limits,
+//! graceful shutdown and tracing setup.
+
+pub use config::Config;
+pub use server::{
+    bind_udp, serve, serve_datagrams, serve_threaded, serve_with_shutdown, shutdown_signal,
+};
+
+/// Read the [`Config`] from CLI flags and env, and start tracing. Call first thing in `main`.
+pub fn init() -> Config {
+    init_with()
+}
+
+/// As [`init`], for servers with extra options of their own. `A` should `#[command(flatten)]` a
+/// [`Config`].
+pub fn init_with<A: clap::Parser>() -> A {
+    let args = A::parse();
+    init_tracing();
+
+    args
+}
+
+/// Install a stderr tracing subscriber, filtered by `RUST_LOG` (defaulting to `info`).
+pub fn init_tracing() {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info"));
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+mod config;
+mod server;
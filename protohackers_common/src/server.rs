@@ -0,0 +1,348 @@
+use crate::Config;
+use std::{future::Future, io, net::SocketAddr, sync::Arc};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+use tracing::{Instrument, Span};
+
+/// Largest payload a single UDP datagram can carry.
+const MAX_DATAGRAM: usize = 65_507;
+
+/// Resolves once the process receives SIGINT (ctrl-c) or SIGTERM.
+pub async fn shutdown_signal() {
+    let interrupt = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("installed SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("shutdown signal received");
+}
+
+/// Accept TCP connections until a shutdown signal arrives, running `handler` as a task per connection.
+pub async fn serve<H, F>(config: &Config, handler: H) -> io::Result<()>
+where
+    H: Fn(TcpStream) -> F,
+    F: Future<Output = ()> + Send + 'static,
+{
+    let listener = TcpListener::bind(config.socket_addr()).await?;
+
+    serve_with_shutdown(listener, config, handler, shutdown_signal()).await
+}
+
+/// As [`serve`], on an already bound listener and stopping when `shutdown` resolves.
+pub async fn serve_with_shutdown<H, F>(
+    listener: TcpListener,
+    config: &Config,
+    handler: H,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    H: Fn(TcpStream) -> F,
+    F: Future<Output = ()> + Send + 'static,
+{
+    run(listener, config, shutdown, |conns, stream, permit, span| {
+        let conn = handler(stream);
+
+        conns.spawn(
+            async move {
+                conn.await;
+                drop(permit);
+                tracing::info!("closing connection");
+            }
+            .instrument(span),
+        );
+    })
+    .await
+}
+
+/// Blocking flavour of [`serve`] for thread-per-connection servers. The handler runs on its own
+/// thread with a plain [`std::net::TcpStream`].
+pub fn serve_threaded<H>(config: &Config, handler: H) -> io::Result<()>
+where
+    H: Fn(std::net::TcpStream) + Send + Sync + 'static,
+{
+    let runtime = tokio::runtime::Runtime::new()?;
+    let handler = Arc::new(handler);
+
+    let res = runtime.block_on(async {
+        let listener = TcpListener::bind(config.socket_addr()).await?;
+
+        run(
+            listener,
+            config,
+            shutdown_signal(),
+            |conns, stream, permit, span| {
+                let handler = Arc::clone(&handler);
+
+                match stream.into_std().and_then(|s| {
+                    s.set_nonblocking(false)?;
+                    Ok(s)
+                }) {
+                    Ok(stream) => {
+                        conns.spawn_blocking(move || {
+                            let _entered = span.enter();
+                            handler(stream);
+                            drop(permit);
+                            tracing::info!("closing connection");
+                        });
+                    }
+                    Err(err) => tracing::warn!(parent: &span, %err, "couldn't hand off stream"),
+                }
+            },
+        )
+        .await
+    });
+
+    // Threads still running after the drain timeout are abandoned rather than waited on
+    runtime.shutdown_background();
+
+    res
+}
+
+/// Answer UDP datagrams until a shutdown signal arrives. `handler` is given each datagram and
+/// its sender, and any bytes it returns are sent back as the reply.
+pub fn serve_datagrams<H>(config: &Config, mut handler: H) -> io::Result<()>
+where
+    H: FnMut(&[u8], SocketAddr) -> Option<Vec<u8>>,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async {
+        let socket = bind_udp(config).await?;
+        let mut buf = vec![0x00; MAX_DATAGRAM];
+
+        let shutdown = shutdown_signal();
+        tokio::pin!(shutdown);
+
+        loop {
+            let (len, src) = tokio::select! {
+                _ = &mut shutdown => break,
+                res = socket.recv_from(&mut buf) => match res {
+                    Ok(received) => received,
+                    Err(err) => {
+                        tracing::warn!(%err, "udp receive failed");
+                        continue;
+                    }
+                },
+            };
+
+            if let Some(reply) = handler(&buf[..len], src) {
+                if let Err(err) = socket.send_to(&reply, src).await {
+                    tracing::warn!(%err, %src, "udp send failed");
+                }
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Bind a UDP socket on the configured address, for servers which run their own receive loop.
+pub async fn bind_udp(config: &Config) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(config.socket_addr()).await?;
+    tracing::info!(addr = %socket.local_addr()?, "listening");
+
+    Ok(socket)
+}
+
+async fn run<S>(
+    listener: TcpListener,
+    config: &Config,
+    shutdown: impl Future<Output = ()>,
+    mut spawn: S,
+) -> io::Result<()>
+where
+    S: FnMut(&mut JoinSet<()>, TcpStream, OwnedSemaphorePermit, Span),
+{
+    let limit = Arc::new(Semaphore::new(config.max_connections));
+    let mut conns = JoinSet::new();
+
+    tracing::info!(
+        addr = %listener.local_addr()?,
+        max_connections = config.max_connections,
+        "listening"
+    );
+
+    tokio::pin!(shutdown);
+
+    loop {
+        // Wait for a free slot before accepting, so excess clients queue in the backlog
+        let next_conn = async {
+            let permit = Arc::clone(&limit)
+                .acquire_owned()
+                .await
+                .expect("semaphore is never closed");
+
+            (permit, listener.accept().await)
+        };
+
+        tokio::select! {
+            _ = &mut shutdown => break,
+            Some(res) = conns.join_next() => log_exit(res),
+            (permit, res) = next_conn => match res {
+                Ok((stream, peer)) => {
+                    let span = tracing::info_span!("conn", %peer);
+                    tracing::info!(parent: &span, "opening connection");
+
+                    spawn(&mut conns, stream, permit, span);
+                }
+                Err(err) => tracing::warn!(%err, "accept failed"),
+            },
+        }
+    }
+
+    drop(listener);
+
+    tracing::info!(open = conns.len(), "draining connections");
+
+    let drained = tokio::time::timeout(config.drain_timeout(), async {
+        while let Some(res) = conns.join_next().await {
+            log_exit(res);
+        }
+    })
+    .await;
+
+    if drained.is_err() {
+        tracing::warn!(open = conns.len(), "drain timed out, dropping connections");
+        conns.abort_all();
+        conns.detach_all();
+    }
+
+    Ok(())
+}
+
+fn log_exit(res: Result<(), tokio::task::JoinError>) {
+    if let Err(err) = res {
+        tracing::error!(%err, "connection task failed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+        time::timeout,
+    };
+
+    async fn echo(mut stream: TcpStream) {
+        let mut buf = [0u8; 32];
+
+        while let Ok(n @ 1..) = stream.read(&mut buf).await {
+            stream.write_all(&buf[..n]).await.ok();
+        }
+    }
+
+    async fn start(
+        config: Config,
+    ) -> (SocketAddr, oneshot::Sender<()>, tokio::task::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            serve_with_shutdown(listener, &config, echo, async {
+                stop_rx.await.ok();
+            })
+            .await
+            .unwrap();
+        });
+
+        (addr, stop_tx, server)
+    }
+
+    async fn roundtrip(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        stream.write_all(b"ping").await.ok()?;
+        let mut buf = [0u8; 4];
+        timeout(Duration::from_millis(200), stream.read_exact(&mut buf))
+            .await
+            .ok()?
+            .ok()?;
+
+        Some(buf.to_vec())
+    }
+
+    #[tokio::test]
+    async fn limits_connections() {
+        let config = Config {
+            max_connections: 1,
+            ..Config::default()
+        };
+        let (addr, _stop, _server) = start(config).await;
+
+        let mut first = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(roundtrip(&mut first).await, Some(b"ping".to_vec()));
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(roundtrip(&mut second).await, None);
+
+        drop(first);
+        let mut buf = [0u8; 4];
+        timeout(Duration::from_secs(1), second.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    #[tokio::test]
+    async fn drains_on_shutdown() {
+        let (addr, stop, server) = start(Config::default()).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(roundtrip(&mut client).await, Some(b"ping".to_vec()));
+
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // No longer accepting, but the open connection is still served
+        assert!(!server.is_finished());
+        assert_eq!(roundtrip(&mut client).await, Some(b"ping".to_vec()));
+
+        drop(client);
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn aborts_after_drain_timeout() {
+        let config = Config {
+            drain_timeout: 0,
+            ..Config::default()
+        };
+        let (addr, stop, server) = start(config).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(roundtrip(&mut client).await, Some(b"ping".to_vec()));
+
+        stop.send(()).unwrap();
+        timeout(Duration::from_secs(1), server)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut buf = [0u8; 4];
+        assert!(matches!(client.read(&mut buf).await, Ok(0) | Err(_)));
+    }
+}
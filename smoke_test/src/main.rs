@@ -1,30 +1,22 @@
 use std::io::prelude::*;
-use std::net::TcpListener;
-use std::thread;
+use std::net::TcpStream;
 
 fn main() -> std::io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:8080")?;
+    let config = protohackers_common::init();
 
-    for stream in listener.incoming() {
-        thread::spawn(move || {
-            eprintln!("Opening connection");
-
-            let mut stream = stream.expect("incoming TCP stream");
-            let mut buf = [0u8; 32];
+    protohackers_common::serve_threaded(&config, handle_client)
+}
 
-            while let Ok(r) = stream.read(&mut buf) {
-                if r == 0 {
-                    eprintln!("No bytes to echo");
-                    break;
-                } else {
-                    eprintln!("Writing {r} bytes to stream");
-                    let _w = stream.write_all(&buf[..r]);
-                }
-            }
+fn handle_client(mut stream: TcpStream) {
+    let mut buf = [0u8; 32];
 
-            eprintln!("Closing connection");
-        });
+    while let Ok(r) = stream.read(&mut buf) {
+        if r == 0 {
+            tracing::debug!("no bytes to echo");
+            break;
+        } else {
+            tracing::debug!(bytes = r, "echoing");
+            let _w = stream.write_all(&buf[..r]);
+        }
     }
-
-    Ok(())
 }
@@ -50,7 +50,7 @@ impl FrameReader {
         loop {
             match self.try_next() {
                 Ok(frame) => return Ok(Some(frame)),
-                Err(err) if matches!(err, FrameError::Incomplete) => {
+                Err(FrameError::Incomplete) => {
                     let bytes_read = self.tcp_stream_r.read_buf(&mut self.stream_buf).await?;
 
                     if bytes_read == 0 {
@@ -5,17 +5,15 @@ use message::Error;
 use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::net::TcpListener;
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio::task;
 use tokio::time::{interval, MissedTickBehavior};
 
 type CameraDb = Arc<Mutex<HashMap<IAmCamera, Vec<Plate>>>>;
 type DispatcherDb = Arc<Mutex<Vec<(IAmDispatcher, UnboundedSender<OutMessage>)>>>;
 
 #[tokio::main]
-async fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").await.unwrap();
+async fn main() -> std::io::Result<()> {
+    let config = protohackers_common::init();
 
     let camera_db: CameraDb = Arc::new(Mutex::new(HashMap::new()));
     let dispatcher_db: DispatcherDb = Arc::new(Mutex::new(Vec::new()));
@@ -25,19 +23,20 @@ async fn main() {
         Arc::clone(&dispatcher_db),
     ));
 
-    loop {
-        let (frame_reader, frame_writer) = frame_rw(listener.accept().await.unwrap().0);
+    protohackers_common::serve(&config, move |tcp_stream| {
+        let (frame_reader, frame_writer) = frame_rw(tcp_stream);
         let (message_sender, message_receiver) = unbounded_channel::<OutMessage>();
 
         tokio::spawn(handle_frames_out(frame_writer, message_receiver));
 
-        task::spawn(handle_frames_in(
+        handle_frames_in(
             frame_reader,
             message_sender,
             Arc::clone(&camera_db),
             Arc::clone(&dispatcher_db),
-        ));
-    }
+        )
+    })
+    .await
 }
 
 async fn handle_frames_out(
@@ -88,6 +87,7 @@ async fn handle_frames_in(
                 }
 
                 InMessage::IAmCamera(camera) => {
+                    tracing::info!(camera.road, camera.mile, camera.limit, "camera identified");
                     camera_db.lock().unwrap().insert(camera, Vec::new());
                     client_identified = true;
 
@@ -120,6 +120,7 @@ async fn handle_frames_in(
                 }
 
                 InMessage::IAmDispatcher(dispatcher) => {
+                    tracing::info!(num_roads = dispatcher.num_roads, "dispatcher identified");
                     client_identified = true;
                     dispatcher_db
                         .lock()
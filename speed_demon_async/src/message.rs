@@ -51,7 +51,7 @@ pub struct IAmCamera {
 }
 
 pub struct IAmDispatcher {
-    num_roads: u8,
+    pub num_roads: u8,
     pub roads: Vec<u16>,
 }
 
@@ -0,0 +1,125 @@
+//! Listing a dir and adding a file, with the path index against the `HashMap` scan it replaced.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+use voracious_code_storage::index::Index;
+
+/// A stand in for the repo's nodes, which are found by name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Node(OsString);
+
+impl Node {
+    fn new(path: &Path) -> Self {
+        Self(path.file_name().unwrap_or_default().to_os_string())
+    }
+}
+
+/// `files` files spread over 100 dirs of 10 subdirs each.
+fn paths(files: usize) -> Vec<PathBuf> {
+    (0..files)
+        .map(|n| PathBuf::from(format!("/d{}/s{}/f{n}.txt", n % 100, n / 100 % 10)))
+        .collect()
+}
+
+/// The old way: every prefix of `path` built by formatting, and a map entry for each.
+fn scan_insert(nodes: &mut HashMap<PathBuf, Node>, path: &Path) {
+    let parts = path.iter().fold(Vec::new(), |mut acc: Vec<PathBuf>, el| {
+        let pre = acc.last().cloned().unwrap_or_default();
+        acc.push(
+            PathBuf::from(format!(
+                "{}/{}",
+                pre.to_string_lossy(),
+                el.to_string_lossy()
+            ))
+            .components()
+            .collect(),
+        );
+        acc
+    });
+
+    for part in parts {
+        nodes.entry(part).or_insert_with_key(|k| Node::new(k));
+    }
+}
+
+/// The old way: a look at every node for those one level below `path`.
+fn scan_children(nodes: &HashMap<PathBuf, Node>, path: &Path) -> Vec<Node> {
+    let expected_len = path.iter().count() + 1;
+
+    let mut entries = nodes
+        .iter()
+        .filter(|(p, _)| p.iter().count() == expected_len && p.starts_with(path))
+        .map(|(_, n)| n.clone())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    entries
+}
+
+fn index_children(nodes: &Index<Node>, path: &Path) -> Vec<Node> {
+    nodes.children(path).map(|(_, n)| n.clone()).collect()
+}
+
+fn list(c: &mut Criterion) {
+    let mut group = c.benchmark_group("list");
+    let dir = Path::new("/d7/s3");
+
+    for files in [1_000, 10_000, 100_000] {
+        let mut scan = HashMap::new();
+        let mut index = Index::new();
+        for path in paths(files) {
+            scan_insert(&mut scan, &path);
+            index.get_or_insert_with(&path, Node::new);
+        }
+        assert_eq!(scan_children(&scan, dir), index_children(&index, dir));
+
+        group.bench_with_input(BenchmarkId::new("hashmap_scan", files), &scan, |b, scan| {
+            b.iter(|| scan_children(scan, dir))
+        });
+        group.bench_with_input(BenchmarkId::new("index", files), &index, |b, index| {
+            b.iter(|| index_children(index, dir))
+        });
+    }
+
+    group.finish();
+}
+
+fn put(c: &mut Criterion) {
+    let mut group = c.benchmark_group("put");
+    let path = Path::new("/new/dir/for/a/file.txt");
+
+    for files in [1_000, 10_000, 100_000] {
+        let mut scan = HashMap::new();
+        let mut index = Index::new();
+        for path in paths(files) {
+            scan_insert(&mut scan, &path);
+            index.get_or_insert_with(&path, Node::new);
+        }
+
+        group.bench_with_input(BenchmarkId::new("hashmap_scan", files), &scan, |b, scan| {
+            b.iter_batched_ref(
+                || scan.clone(),
+                |scan| scan_insert(scan, path),
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("index", files), &index, |b, index| {
+            b.iter_batched_ref(
+                || index.clone(),
+                |index| {
+                    index.get_or_insert_with(path, Node::new);
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, list, put);
+criterion_main!(benches);
@@ -0,0 +1,116 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Read and/or write access to everything under `prefix` for connections which `AUTH` with
+/// `token`. An empty token applies to connections which haven't authenticated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    token: String,
+    read: bool,
+    write: bool,
+    prefix: PathBuf,
+}
+
+impl FromStr for Grant {
+    type Err = String;
+
+    /// Parse `token:perms:prefix`, where `perms` is `r`, `w` or `rw`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.rsplitn(3, ':');
+        let (Some(prefix), Some(perms), Some(token)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("expected token:perms:prefix, got {s}"));
+        };
+
+        if perms.is_empty() || !perms.chars().all(|c| "rw".contains(c)) {
+            return Err(format!("perms must be r, w or rw, got {perms}"));
+        }
+
+        if !prefix.starts_with('/') {
+            return Err(format!("prefix must be an absolute path, got {prefix}"));
+        }
+
+        Ok(Self {
+            token: token.to_string(),
+            read: perms.contains('r'),
+            write: perms.contains('w'),
+            prefix: PathBuf::from(prefix),
+        })
+    }
+}
+
+/// Who may read and write where. With no grants at all, anyone may do anything.
+#[derive(Debug, Clone, Default)]
+pub struct Acl(Vec<Grant>);
+
+impl Acl {
+    pub fn new(grants: Vec<Grant>) -> Self {
+        Self(grants)
+    }
+
+    /// Whether `token` is one a connection may `AUTH` with.
+    pub fn knows(&self, token: &str) -> bool {
+        !token.is_empty() && self.0.iter().any(|g| g.token == token)
+    }
+
+    pub fn allows(&self, token: &str, access: Access, path: &Path) -> bool {
+        self.0.is_empty()
+            || self.0.iter().any(|g| {
+                g.token == token
+                    && path.starts_with(&g.prefix)
+                    && match access {
+                        Access::Read => g.read,
+                        Access::Write => g.write,
+                    }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grants() {
+        let acl = Acl::new(vec![
+            "admin:rw:/".parse().unwrap(),
+            "dev:r:/".parse().unwrap(),
+            "dev:w:/src".parse().unwrap(),
+            ":r:/pub".parse().unwrap(),
+        ]);
+
+        assert!(acl.knows("dev"));
+        assert!(!acl.knows("nobody"));
+        assert!(!acl.knows(""));
+
+        assert!(acl.allows("admin", Access::Write, Path::new("/a.txt")));
+        assert!(acl.allows("dev", Access::Read, Path::new("/a.txt")));
+        assert!(!acl.allows("dev", Access::Write, Path::new("/a.txt")));
+        assert!(acl.allows("dev", Access::Write, Path::new("/src/a.rs")));
+        assert!(!acl.allows("dev", Access::Write, Path::new("/srcs/a.rs")));
+        assert!(acl.allows("", Access::Read, Path::new("/pub/a.txt")));
+        assert!(!acl.allows("", Access::Read, Path::new("/a.txt")));
+
+        assert!(Acl::default().allows("", Access::Write, Path::new("/a.txt")));
+    }
+
+    #[test]
+    fn bad_grants() {
+        assert!("admin:rw".parse::<Grant>().is_err());
+        assert!("admin:x:/".parse::<Grant>().is_err());
+        assert!("admin::/".parse::<Grant>().is_err());
+        assert!("admin:r:src".parse::<Grant>().is_err());
+        assert_eq!(
+            "a:b:r:/".parse::<Grant>().map(|g| g.token),
+            Ok("a:b".to_string())
+        );
+    }
+}
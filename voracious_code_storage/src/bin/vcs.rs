@@ -0,0 +1,136 @@
+//! Command line client for a voracious code storage server.
+
+use clap::{Parser, Subcommand};
+use std::{
+    net::TcpStream,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+use voracious_code_storage::client::{Client, Entry, Error};
+
+#[derive(Parser)]
+#[command(name = "vcs")]
+struct Args {
+    /// Server to talk to
+    #[arg(long, env = "VCS_ADDR", default_value = "127.0.0.1:8080")]
+    addr: String,
+
+    /// Token to `AUTH` with, for servers which control access
+    #[arg(long, env = "VCS_TOKEN")]
+    token: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Upload a local file to a path in the repo
+    Push { local: PathBuf, remote: String },
+    /// Download a file from the repo, at its latest revision or `--rev`
+    Pull {
+        remote: String,
+        local: PathBuf,
+        #[arg(long)]
+        rev: Option<usize>,
+    },
+    /// List a dir in the repo
+    Ls {
+        #[arg(default_value = "/")]
+        dir: String,
+    },
+    /// Upload every file under a local dir to the same place under a dir in the repo. Files only
+    /// in the repo are left alone
+    SyncDir { local: PathBuf, remote: String },
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("vcs: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let mut client = Client::connect(&args.addr)?;
+    if let Some(token) = &args.token {
+        client.auth(token)?;
+    }
+    // Files are shipped as they are, whatever they hold
+    client.binary()?;
+
+    match args.command {
+        Command::Push { local, remote } => {
+            let rev = client.put(&remote, &std::fs::read(local)?)?;
+            println!("{remote} r{rev}");
+        }
+        Command::Pull { remote, local, rev } => {
+            std::fs::write(local, client.get(&remote, rev)?)?;
+        }
+        Command::Ls { dir } => {
+            for entry in client.list(&dir)? {
+                match entry {
+                    Entry::File { name, rev } => println!("{name} r{rev}"),
+                    Entry::Deleted { name } => println!("{name} (deleted)"),
+                    Entry::Dir { name } => println!("{name}/"),
+                }
+            }
+        }
+        Command::SyncDir { local, remote } => sync_dir(&mut client, &local, &remote)?,
+    }
+
+    Ok(())
+}
+
+/// Push everything under `local`, carrying on past files the server refuses and failing at the
+/// end if there were any.
+fn sync_dir(client: &mut Client<TcpStream>, local: &Path, remote: &str) -> Result<(), Error> {
+    let mut files = Vec::new();
+    local_files(local, &mut files)?;
+    files.sort();
+
+    let mut refused = 0;
+    for file in files {
+        let relative = file.strip_prefix(local).unwrap();
+        let path = format!(
+            "{}/{}",
+            remote.trim_end_matches('/'),
+            relative.to_string_lossy()
+        );
+
+        match client.put(&path, &std::fs::read(&file)?) {
+            Ok(rev) => println!("{path} r{rev}"),
+            Err(err @ (Error::Server(_) | Error::Path(_))) => {
+                eprintln!("vcs: {path}: {err}");
+                refused += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    match refused {
+        0 => Ok(()),
+        n => Err(Error::Io(std::io::Error::other(format!(
+            "{n} files were not stored"
+        )))),
+    }
+}
+
+fn local_files(dir: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+
+        if entry.file_type()?.is_dir() {
+            local_files(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+
+    Ok(())
+}
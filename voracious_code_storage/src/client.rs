@@ -0,0 +1,442 @@
+//! Blocking and async clients. Both wait for the server's `READY` before each request, and turn
+//! `OK` responses into values and `ERR` ones into [`Error::Server`].
+
+use std::{
+    fmt::Display,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The server answered `ERR`, with this message
+    Server(String),
+    /// The server said something this client doesn't understand
+    Protocol(String),
+    /// A path which can't be sent, as it has whitespace in it
+    Path(String),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::Server(msg) if msg.is_empty() => write!(f, "server error"),
+            Error::Server(msg) => write!(f, "server error: {msg}"),
+            Error::Protocol(line) => write!(f, "unexpected response: {line:?}"),
+            Error::Path(path) => write!(f, "invalid path: {path:?}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// One line of a `LIST` response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    File { name: String, rev: usize },
+    Deleted { name: String },
+    Dir { name: String },
+}
+
+impl FromStr for Entry {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let bad = || Error::Protocol(line.to_string());
+        let (name, kind) = line.rsplit_once(' ').ok_or_else(bad)?;
+
+        match kind {
+            "DIR" => Ok(Entry::Dir {
+                name: name.strip_suffix('/').ok_or_else(bad)?.to_string(),
+            }),
+            "DELETED" => Ok(Entry::Deleted {
+                name: name.to_string(),
+            }),
+            rev => Ok(Entry::File {
+                name: name.to_string(),
+                rev: parse_rev(rev).map_err(|_| bad())?,
+            }),
+        }
+    }
+}
+
+/// A blocking connection to a server.
+pub struct Client<S> {
+    stream: BufReader<S>,
+}
+
+impl Client<TcpStream> {
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self, Error> {
+        Self::new(TcpStream::connect(addr)?)
+    }
+}
+
+impl<S: Read + Write> Client<S> {
+    /// Talk to the server at the other end of `stream`, once it's ready.
+    pub fn new(stream: S) -> Result<Self, Error> {
+        let mut client = Self {
+            stream: BufReader::new(stream),
+        };
+        client.ready()?;
+
+        Ok(client)
+    }
+
+    /// Store `data` at `path`, returning its revision.
+    pub fn put(&mut self, path: &str, data: &[u8]) -> Result<usize, Error> {
+        let ok = self.request(&put_request(path, data)?)?;
+        self.ready()?;
+
+        parse_rev(&ok)
+    }
+
+    /// The contents of `path`, at revision `rev` or its latest.
+    pub fn get(&mut self, path: &str, rev: Option<usize>) -> Result<Vec<u8>, Error> {
+        let len = parse_len(&self.request(&get_request(path, rev)?)?)?;
+
+        let mut data = vec![0; len];
+        self.stream.read_exact(&mut data)?;
+        self.ready()?;
+
+        Ok(data)
+    }
+
+    /// The entries directly in `dir`.
+    pub fn list(&mut self, dir: &str) -> Result<Vec<Entry>, Error> {
+        let len = parse_len(&self.request(&list_request(dir)?)?)?;
+
+        let entries = (0..len)
+            .map(|_| self.line()?.parse())
+            .collect::<Result<_, _>>()?;
+        self.ready()?;
+
+        Ok(entries)
+    }
+
+    /// The server's usage message.
+    pub fn help(&mut self) -> Result<String, Error> {
+        let ok = self.request(b"HELP\n")?;
+        self.ready()?;
+
+        Ok(ok)
+    }
+
+    /// Authenticate, for servers which control access.
+    pub fn auth(&mut self, token: &str) -> Result<(), Error> {
+        self.request(format!("AUTH {token}\n").as_bytes())?;
+        self.ready()
+    }
+
+    /// Switch to `MODE BINARY`, so any contents can be put and got.
+    pub fn binary(&mut self) -> Result<(), Error> {
+        self.request(b"MODE BINARY\n")?;
+        self.ready()
+    }
+
+    /// Send a request and read the first line of its response, up to any further content.
+    fn request(&mut self, request: &[u8]) -> Result<String, Error> {
+        let inner = self.stream.get_mut();
+        inner.write_all(request)?;
+        inner.flush()?;
+
+        let line = self.line()?;
+        let res = status(&line);
+        if res.is_err() {
+            // The server is ready for the next request regardless
+            self.ready().ok();
+        }
+
+        res
+    }
+
+    fn ready(&mut self) -> Result<(), Error> {
+        expect_ready(&self.line()?)
+    }
+
+    fn line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        self.stream.read_line(&mut line)?;
+
+        strip_line(line)
+    }
+}
+
+/// An async connection to a server.
+pub struct AsyncClient<S> {
+    stream: tokio::io::BufReader<S>,
+}
+
+impl AsyncClient<tokio::net::TcpStream> {
+    pub async fn connect(addr: impl tokio::net::ToSocketAddrs) -> Result<Self, Error> {
+        Self::new(tokio::net::TcpStream::connect(addr).await?).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncClient<S> {
+    /// As [`Client::new`].
+    pub async fn new(stream: S) -> Result<Self, Error> {
+        let mut client = Self {
+            stream: tokio::io::BufReader::new(stream),
+        };
+        client.ready().await?;
+
+        Ok(client)
+    }
+
+    /// As [`Client::put`].
+    pub async fn put(&mut self, path: &str, data: &[u8]) -> Result<usize, Error> {
+        let ok = self.request(&put_request(path, data)?).await?;
+        self.ready().await?;
+
+        parse_rev(&ok)
+    }
+
+    /// As [`Client::get`].
+    pub async fn get(&mut self, path: &str, rev: Option<usize>) -> Result<Vec<u8>, Error> {
+        let len = parse_len(&self.request(&get_request(path, rev)?).await?)?;
+
+        let mut data = vec![0; len];
+        self.stream.read_exact(&mut data).await?;
+        self.ready().await?;
+
+        Ok(data)
+    }
+
+    /// As [`Client::list`].
+    pub async fn list(&mut self, dir: &str) -> Result<Vec<Entry>, Error> {
+        let len = parse_len(&self.request(&list_request(dir)?).await?)?;
+
+        let mut entries = Vec::with_capacity(len);
+        for _ in 0..len {
+            entries.push(self.line().await?.parse()?);
+        }
+        self.ready().await?;
+
+        Ok(entries)
+    }
+
+    /// As [`Client::help`].
+    pub async fn help(&mut self) -> Result<String, Error> {
+        let ok = self.request(b"HELP\n").await?;
+        self.ready().await?;
+
+        Ok(ok)
+    }
+
+    /// As [`Client::auth`].
+    pub async fn auth(&mut self, token: &str) -> Result<(), Error> {
+        self.request(format!("AUTH {token}\n").as_bytes()).await?;
+        self.ready().await
+    }
+
+    /// As [`Client::binary`].
+    pub async fn binary(&mut self) -> Result<(), Error> {
+        self.request(b"MODE BINARY\n").await?;
+        self.ready().await
+    }
+
+    async fn request(&mut self, request: &[u8]) -> Result<String, Error> {
+        let inner = self.stream.get_mut();
+        inner.write_all(request).await?;
+        inner.flush().await?;
+
+        let line = self.line().await?;
+        let res = status(&line);
+        if res.is_err() {
+            self.ready().await.ok();
+        }
+
+        res
+    }
+
+    async fn ready(&mut self) -> Result<(), Error> {
+        expect_ready(&self.line().await?)
+    }
+
+    async fn line(&mut self) -> Result<String, Error> {
+        let mut line = String::new();
+        self.stream.read_line(&mut line).await?;
+
+        strip_line(line)
+    }
+}
+
+fn put_request(path: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut request = format!("PUT {} {}\n", checked(path)?, data.len()).into_bytes();
+    request.extend(data);
+
+    Ok(request)
+}
+
+fn get_request(path: &str, rev: Option<usize>) -> Result<Vec<u8>, Error> {
+    let path = checked(path)?;
+
+    Ok(match rev {
+        Some(rev) => format!("GET {path} r{rev}\n"),
+        None => format!("GET {path}\n"),
+    }
+    .into_bytes())
+}
+
+fn list_request(dir: &str) -> Result<Vec<u8>, Error> {
+    Ok(format!("LIST {}\n", checked(dir)?).into_bytes())
+}
+
+/// `path`, if it can be sent. One with whitespace would be split up, and a PUT's body taken for
+/// requests.
+fn checked(path: &str) -> Result<&str, Error> {
+    match path.is_empty() || path.contains(char::is_whitespace) {
+        true => Err(Error::Path(path.to_string())),
+        false => Ok(path),
+    }
+}
+
+/// A line without its newline. Running out of input first means the server hung up.
+fn strip_line(mut line: String) -> Result<String, Error> {
+    if line.pop() != Some('\n') {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    Ok(line)
+}
+
+/// What follows `OK` in a response line, or the server's error.
+fn status(line: &str) -> Result<String, Error> {
+    let (status, rest) = line.split_once(' ').unwrap_or((line, ""));
+
+    match status {
+        "OK" => Ok(rest.to_string()),
+        "ERR" => Err(Error::Server(rest.to_string())),
+        _ => Err(Error::Protocol(line.to_string())),
+    }
+}
+
+fn expect_ready(line: &str) -> Result<(), Error> {
+    match line {
+        "READY" => Ok(()),
+        _ => Err(Error::Protocol(line.to_string())),
+    }
+}
+
+fn parse_rev(s: &str) -> Result<usize, Error> {
+    s.strip_prefix('r')
+        .and_then(|n| n.parse().ok())
+        .ok_or_else(|| Error::Protocol(s.to_string()))
+}
+
+fn parse_len(s: &str) -> Result<usize, Error> {
+    s.parse().map_err(|_| Error::Protocol(s.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A server which has already said everything in `responses`.
+    struct Canned {
+        responses: io::Cursor<Vec<u8>>,
+        requests: Vec<u8>,
+    }
+
+    impl Canned {
+        fn new(responses: &[u8]) -> Self {
+            Self {
+                responses: io::Cursor::new(responses.to_vec()),
+                requests: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Canned {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            Read::read(&mut self.responses, buf)
+        }
+    }
+
+    impl Write for Canned {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut self.requests, buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn responses() {
+        let mut client = Client::new(Canned::new(
+            b"READY\nOK r2\nREADY\nOK 4\nabc\nREADY\nOK 3\na/ DIR\nb.txt r1\nc.txt DELETED\nREADY\n\
+              ERR no such file\nREADY\nOK usage: HELP\nREADY\n",
+        ))
+        .unwrap();
+
+        assert_eq!(client.put("/a.txt", b"abc\n").unwrap(), 2);
+        assert_eq!(client.get("/a.txt", Some(2)).unwrap(), b"abc\n");
+        assert_eq!(
+            client.list("/").unwrap(),
+            vec![
+                Entry::Dir { name: "a".into() },
+                Entry::File {
+                    name: "b.txt".into(),
+                    rev: 1
+                },
+                Entry::Deleted {
+                    name: "c.txt".into()
+                },
+            ]
+        );
+        assert!(matches!(
+            client.get("/b.txt", None),
+            Err(Error::Server(msg)) if msg == "no such file"
+        ));
+        assert_eq!(client.help().unwrap(), "usage: HELP");
+        assert!(matches!(client.help(), Err(Error::Io(_))));
+
+        assert_eq!(
+            client.stream.get_ref().requests,
+            b"PUT /a.txt 4\nabc\nGET /a.txt r2\nLIST /\nGET /b.txt\nHELP\nHELP\n"
+        );
+    }
+
+    #[test]
+    fn unexpected_responses() {
+        assert!(matches!(
+            Client::new(Canned::new(b"HELLO\n")),
+            Err(Error::Protocol(line)) if line == "HELLO"
+        ));
+
+        let mut client = Client::new(Canned::new(b"READY\nOK two\nREADY\n")).unwrap();
+        assert!(matches!(client.put("/a b", b""), Err(Error::Path(_))));
+        assert!(matches!(client.put("/a", b""), Err(Error::Protocol(_))));
+        assert_eq!(client.stream.get_ref().requests, b"PUT /a 0\n");
+    }
+
+    #[tokio::test]
+    async fn async_responses() {
+        let (near, mut far) = tokio::io::duplex(1024);
+        far.write_all(b"READY\nOK r1\nREADY\nOK 3\nhi\nREADY\nERR\nREADY\n")
+            .await
+            .unwrap();
+
+        let mut client = AsyncClient::new(near).await.unwrap();
+        assert_eq!(client.put("/a.txt", b"hi").await.unwrap(), 1);
+        assert_eq!(client.get("/a.txt", None).await.unwrap(), b"hi\n");
+        assert!(matches!(client.list("/").await, Err(Error::Server(msg)) if msg.is_empty()));
+
+        drop(client);
+        let mut requests = String::new();
+        far.read_to_string(&mut requests).await.unwrap();
+        assert_eq!(requests, "PUT /a.txt 2\nhiGET /a.txt\nLIST /\n");
+    }
+}
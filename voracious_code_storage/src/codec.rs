@@ -0,0 +1,256 @@
+use crate::{error::Error, parsers::Op};
+use bytes::{Buf, Bytes, BytesMut};
+use std::{io, path::PathBuf, time::Duration};
+use tokio::time::Instant;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Longest request line accepted before giving up on the connection.
+const MAX_LINE: usize = 4096;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    /// A PUT along with its complete body
+    Put(PathBuf, Bytes),
+    Op(Op),
+    /// A line none of the parsers recognised
+    Unknown,
+}
+
+#[derive(Debug)]
+enum State {
+    Line,
+    Body {
+        path: PathBuf,
+        len: usize,
+    },
+    /// Throwing away the body of a PUT which was too large
+    Skip(usize),
+}
+
+/// Frames requests as a command line, followed by `len` bytes of data for a PUT. Requests may be
+/// pipelined; each is decoded once it is complete.
+#[derive(Debug)]
+pub struct VcsCodec {
+    max_put: usize,
+    state: State,
+    /// When the body being read last grew, to spot stalled uploads
+    progress: Instant,
+}
+
+impl VcsCodec {
+    pub fn new(max_put: usize) -> Self {
+        Self {
+            max_put,
+            state: State::Line,
+            progress: Instant::now(),
+        }
+    }
+
+    /// Whether a PUT body has been partly received, but none of it has arrived for `timeout`.
+    pub fn stalled(&self, timeout: Duration) -> bool {
+        !matches!(self.state, State::Line) && self.progress.elapsed() >= timeout
+    }
+
+    /// Handle a request line, returning `None` for a PUT whose body is still to be read.
+    fn line(&mut self, line: &[u8]) -> Option<Request> {
+        let request = match crate::parsers::parse(line) {
+            Ok((_, Op::Put(_, len))) if len > self.max_put => {
+                // Answer now, but the body still has to be read past to find the next request
+                self.state = State::Skip(len);
+                self.progress = Instant::now();
+
+                Request::Op(Op::Err(Error::TooLarge))
+            }
+            Ok((_, Op::Put(path, len))) => {
+                self.state = State::Body { path, len };
+                self.progress = Instant::now();
+
+                return None;
+            }
+            Ok((_, op)) => Request::Op(op),
+            Err(_) => Request::Unknown,
+        };
+
+        Some(request)
+    }
+}
+
+impl Decoder for VcsCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            match &mut self.state {
+                State::Line => {
+                    let Some(end) = src.iter().position(|b| *b == b'\n') else {
+                        if src.len() > MAX_LINE {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "request line too long",
+                            ));
+                        }
+
+                        return Ok(None);
+                    };
+
+                    let line = src.split_to(end + 1);
+
+                    if let Some(request) = self.line(&line) {
+                        return Ok(Some(request));
+                    }
+                }
+                State::Body { len, .. } => {
+                    if src.len() < *len {
+                        src.reserve(*len - src.len());
+                        self.progress = Instant::now();
+
+                        return Ok(None);
+                    }
+
+                    let data = src.split_to(*len).freeze();
+                    let State::Body { path, .. } = std::mem::replace(&mut self.state, State::Line)
+                    else {
+                        unreachable!()
+                    };
+
+                    return Ok(Some(Request::Put(path, data)));
+                }
+                State::Skip(remaining) => {
+                    let n = (*remaining).min(src.len());
+                    src.advance(n);
+                    *remaining -= n;
+                    self.progress = Instant::now();
+
+                    if *remaining > 0 {
+                        return Ok(None);
+                    }
+
+                    self.state = State::Line;
+                }
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(request) = self.decode(src)? {
+            return Ok(Some(request));
+        }
+
+        match std::mem::replace(&mut self.state, State::Line) {
+            // Never store a partial body
+            State::Body { .. } => {
+                src.clear();
+                Ok(Some(Request::Op(Op::Err(Error::Truncated))))
+            }
+            State::Skip(_) => Ok(None),
+            // A final line without its newline
+            State::Line if !src.is_empty() => {
+                let line = src.split();
+                let request = self.line(&line);
+                self.state = State::Line;
+
+                Ok(Some(
+                    request.unwrap_or(Request::Op(Op::Err(Error::Truncated))),
+                ))
+            }
+            State::Line => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Vec<u8>> for VcsCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Vec<u8>, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(codec: &mut VcsCodec, src: &mut BytesMut) -> Vec<Request> {
+        std::iter::from_fn(|| codec.decode(src).unwrap()).collect()
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let mut codec = VcsCodec::new(1024);
+        let mut src = BytesMut::from(&b"PUT /a.txt 4\none\nGET /a.txt\nHELP\nPUT /b.txt 3\ntw"[..]);
+
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![
+                Request::Put(PathBuf::from("/a.txt"), Bytes::from_static(b"one\n")),
+                Request::Op(Op::Get(PathBuf::from("/a.txt"), usize::MAX, None)),
+                Request::Op(Op::Help),
+            ]
+        );
+
+        src.extend_from_slice(b"onot a request\n");
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![
+                Request::Put(PathBuf::from("/b.txt"), Bytes::from_static(b"two")),
+                Request::Unknown,
+            ]
+        );
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn oversized_put_is_skipped() {
+        let mut codec = VcsCodec::new(4);
+        let mut src = BytesMut::from(&b"PUT /a.txt 10\n01234"[..]);
+
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![Request::Op(Op::Err(Error::TooLarge))]
+        );
+
+        src.extend_from_slice(b"56789HELP\n");
+        assert_eq!(
+            decode_all(&mut codec, &mut src),
+            vec![Request::Op(Op::Help)]
+        );
+    }
+
+    #[test]
+    fn truncated_body_is_an_error() {
+        let mut codec = VcsCodec::new(1024);
+        let mut src = BytesMut::from(&b"PUT /a.txt 10\nshort"[..]);
+
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert_eq!(
+            codec.decode_eof(&mut src).unwrap(),
+            Some(Request::Op(Op::Err(Error::Truncated)))
+        );
+        assert_eq!(codec.decode_eof(&mut src).unwrap(), None);
+    }
+
+    #[test]
+    fn stalls_only_mid_body() {
+        let mut codec = VcsCodec::new(1024);
+        let mut src = BytesMut::from(&b"HELP\n"[..]);
+
+        codec.decode(&mut src).unwrap();
+        assert!(!codec.stalled(Duration::ZERO));
+
+        src.extend_from_slice(b"PUT /a.txt 10\nabc");
+        codec.decode(&mut src).unwrap();
+        assert!(codec.stalled(Duration::ZERO));
+        assert!(!codec.stalled(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn long_line_is_rejected() {
+        let mut codec = VcsCodec::new(1024);
+        let mut src = BytesMut::from(&[b'a'; MAX_LINE + 1][..]);
+
+        assert!(codec.decode(&mut src).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::io;
+
+/// Encode `new` as a line-based delta against `base`.
+///
+/// The delta is a series of ops, each on a header line:
+/// - `c <line> <count>` copies `count` lines of `base` starting at `line`
+/// - `i <len>` is followed by `len` bytes to insert verbatim
+pub fn encode(base: &[u8], new: &[u8]) -> Vec<u8> {
+    let base_lines = lines(base);
+    let new_lines = lines(new);
+
+    let mut delta = Vec::new();
+
+    for op in capture_diff_slices(Algorithm::Myers, &base_lines, &new_lines) {
+        match op {
+            DiffOp::Equal { old_index, len, .. } => {
+                delta.extend(format!("c {old_index} {len}\n").as_bytes());
+            }
+            DiffOp::Insert {
+                new_index, new_len, ..
+            }
+            | DiffOp::Replace {
+                new_index, new_len, ..
+            } => {
+                let inserted = new_lines[new_index..new_index + new_len].concat();
+                delta.extend(format!("i {}\n", inserted.len()).as_bytes());
+                delta.extend(inserted);
+            }
+            DiffOp::Delete { .. } => {}
+        }
+    }
+
+    delta
+}
+
+/// Rebuild the contents a delta from [`encode`] describes, given the same `base`.
+pub fn apply(base: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let base_lines = lines(base);
+    let mut out = Vec::with_capacity(base.len());
+    let mut rest = delta;
+
+    while !rest.is_empty() {
+        let header_len = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| invalid("unterminated delta op"))?;
+        let header = std::str::from_utf8(&rest[..header_len]).map_err(|_| invalid("bad op"))?;
+        rest = &rest[header_len + 1..];
+
+        let mut parts = header.split(' ');
+        let op = parts.next();
+        let mut next_num = || {
+            parts
+                .next()
+                .and_then(|n| n.parse::<usize>().ok())
+                .ok_or_else(|| invalid("bad op argument"))
+        };
+
+        match op {
+            Some("c") => {
+                let (line, count) = (next_num()?, next_num()?);
+                let copied = base_lines
+                    .get(line..line.saturating_add(count))
+                    .ok_or_else(|| invalid("copy out of range"))?;
+
+                out.extend(copied.concat());
+            }
+            Some("i") => {
+                let len = next_num()?;
+                let inserted = rest
+                    .get(..len)
+                    .ok_or_else(|| invalid("insert out of range"))?;
+
+                out.extend(inserted);
+                rest = &rest[len..];
+            }
+            _ => return Err(invalid("unknown op")),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Split into lines, each keeping its trailing newline.
+fn lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|b| *b == b'\n').collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("delta: {msg}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(base: &[u8], new: &[u8]) {
+        let delta = encode(base, new);
+        assert_eq!(apply(base, &delta).unwrap(), new);
+    }
+
+    #[test]
+    fn edits() {
+        let base = b"one\ntwo\nthree\nfour\n";
+
+        round_trip(base, b"one\ntwo\nthree\nfour\n");
+        round_trip(base, b"one\n2\nthree\nfour\nfive\n");
+        round_trip(base, b"zero\none\nfour\n");
+        round_trip(base, b"");
+        round_trip(b"", base);
+    }
+
+    #[test]
+    fn missing_trailing_newline() {
+        round_trip(b"one\ntwo", b"one\ntwo\nthree");
+        round_trip(b"one\ntwo\n", b"one\ntwo");
+    }
+
+    #[test]
+    fn small_edit_is_small() {
+        let base = (0..1000).map(|n| format!("line {n}\n")).collect::<String>();
+        let new = base.replace("line 500\n", "line five hundred\n");
+
+        let delta = encode(base.as_bytes(), new.as_bytes());
+        assert!(delta.len() < 64, "{}", String::from_utf8_lossy(&delta));
+    }
+
+    #[test]
+    fn corrupt_delta() {
+        assert!(apply(b"one\n", b"c 0 2\n").is_err());
+        assert!(apply(b"one\n", b"i 10\nshort").is_err());
+        assert!(apply(b"one\n", b"x\n").is_err());
+    }
+}
@@ -3,6 +3,24 @@ pub enum Error {
     Put,
     Get,
     List,
+    Tree,
+    Log,
+    Diff,
+    Delete,
+    Move,
+    Mode,
+    Tag,
+    TagExists,
+    NoSuchTag,
+    Binary,
+    Quota,
+    Auth,
+    Denied,
+    TooLarge,
+    Truncated,
+    Timeout,
+    Storage,
+    Corrupt,
 }
 
 impl std::error::Error for Error {}
@@ -13,6 +31,24 @@ impl std::fmt::Display for Error {
             Error::Put => "ERR usage: PUT file length newline data\n",
             Error::Get => "ERR usage: GET file rev\n",
             Error::List => "ERR usage: LIST dir\n",
+            Error::Tree => "ERR usage: TREE dir\n",
+            Error::Log => "ERR usage: LOG file\n",
+            Error::Diff => "ERR usage: DIFF file rev rev\n",
+            Error::Delete => "ERR usage: DELETE file\n",
+            Error::Move => "ERR usage: MOVE file file\n",
+            Error::Mode => "ERR usage: MODE TEXT|BINARY\n",
+            Error::Tag => "ERR usage: TAG name\n",
+            Error::TagExists => "ERR tag already exists\n",
+            Error::NoSuchTag => "ERR no such tag\n",
+            Error::Binary => "ERR file is binary, switch with MODE BINARY\n",
+            Error::Quota => "ERR quota exceeded\n",
+            Error::Auth => "ERR invalid token\n",
+            Error::Denied => "ERR permission denied\n",
+            Error::TooLarge => "ERR file too large\n",
+            Error::Truncated => "ERR upload truncated\n",
+            Error::Timeout => "ERR upload timed out\n",
+            Error::Storage => "ERR storage failure\n",
+            Error::Corrupt => "ERR revision is corrupt\n",
         };
 
         write!(f, "{msg}")
@@ -0,0 +1,54 @@
+/// Characters with a special meaning in a glob. Neither may appear in a file name.
+pub const SPECIAL: [char; 2] = ['*', '?'];
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters and `?` any one.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+
+    let (mut p, mut n) = (0, 0);
+    // Where to resume if the last `*` needs to swallow another character
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", ".rs"));
+        assert!(!matches("*.rs", "main.rs.bak"));
+        assert!(matches("*.rs*", "main.rs.bak"));
+        assert!(matches("m??n.*", "main.rs"));
+        assert!(!matches("m?n.*", "main.rs"));
+        assert!(matches("*a*b*", "xxaxxbxx"));
+        assert!(!matches("*a*b", "xxbxxa"));
+        assert!(matches("*", ""));
+        assert!(matches("exact", "exact"));
+    }
+}
@@ -0,0 +1,236 @@
+//! Path-keyed index the server keeps its files and dirs in.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+/// Values keyed by path, held as a tree of path components. Finding a path costs its depth, and
+/// a dir's entries come out in name order without looking at anything outside it.
+#[derive(Debug, Clone)]
+pub struct Index<T> {
+    root: Slot<T>,
+    len: usize,
+}
+
+/// A path component, which may have a value of its own and/or things below it.
+#[derive(Debug, Clone)]
+struct Slot<T> {
+    value: Option<T>,
+    children: BTreeMap<OsString, Slot<T>>,
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            children: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> Default for Index<T> {
+    fn default() -> Self {
+        Self {
+            root: Slot::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> Index<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&T> {
+        self.slot(path)?.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, path: &Path) -> Option<&mut T> {
+        path.iter()
+            .try_fold(&mut self.root, |slot, part| slot.children.get_mut(part))?
+            .value
+            .as_mut()
+    }
+
+    /// Set the value at `path`, returning the one it replaced.
+    pub fn insert(&mut self, path: &Path, value: T) -> Option<T> {
+        let slot = path.iter().fold(&mut self.root, |slot, part| {
+            slot.children.entry(part.to_os_string()).or_default()
+        });
+
+        let old = slot.value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+
+        old
+    }
+
+    /// The value at `path`, first giving it and any of its ancestors without one a value from
+    /// `fill`. `path` must not be empty.
+    pub fn get_or_insert_with(&mut self, path: &Path, mut fill: impl FnMut(&Path) -> T) -> &mut T {
+        let mut slot = &mut self.root;
+        let mut at = PathBuf::new();
+
+        for part in path.iter() {
+            at.push(part);
+            slot = slot.children.entry(part.to_os_string()).or_default();
+
+            if slot.value.is_none() {
+                slot.value = Some(fill(&at));
+                self.len += 1;
+            }
+        }
+
+        slot.value.as_mut().expect("empty path")
+    }
+
+    /// Take the value at `path`, tidying away any components left with nothing in or below them.
+    pub fn remove(&mut self, path: &Path) -> Option<T> {
+        fn take<T>(slot: &mut Slot<T>, parts: &[&OsStr]) -> Option<T> {
+            let Some((part, rest)) = parts.split_first() else {
+                return slot.value.take();
+            };
+
+            let child = slot.children.get_mut(*part)?;
+            let value = take(child, rest);
+
+            if child.value.is_none() && child.children.is_empty() {
+                slot.children.remove(*part);
+            }
+
+            value
+        }
+
+        let value = take(&mut self.root, &path.iter().collect::<Vec<_>>());
+        if value.is_some() {
+            self.len -= 1;
+        }
+
+        value
+    }
+
+    /// The values directly inside `path`, by name in order.
+    pub fn children(&self, path: &Path) -> impl Iterator<Item = (&OsStr, &T)> {
+        self.slot(path)
+            .into_iter()
+            .flat_map(|slot| &slot.children)
+            .filter_map(|(name, child)| Some((name.as_os_str(), child.value.as_ref()?)))
+    }
+
+    /// The values at and below `path`, each dir's entries in order and each directly followed by
+    /// what's below it.
+    pub fn subtree(&self, path: &Path) -> impl Iterator<Item = (PathBuf, &T)> {
+        let mut stack = self
+            .slot(path)
+            .map(|slot| (path.to_path_buf(), slot))
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        std::iter::from_fn(move || loop {
+            let (path, slot) = stack.pop()?;
+
+            stack.extend(
+                slot.children
+                    .iter()
+                    .rev()
+                    .map(|(name, child)| (path.join(name), child)),
+            );
+
+            if let Some(value) = &slot.value {
+                return Some((path, value));
+            }
+        })
+    }
+
+    /// Everything in the index, as [`Self::subtree`] orders it.
+    pub fn iter(&self) -> impl Iterator<Item = (PathBuf, &T)> {
+        self.subtree(Path::new(""))
+    }
+
+    fn slot(&self, path: &Path) -> Option<&Slot<T>> {
+        path.iter()
+            .try_fold(&self.root, |slot, part| slot.children.get(part))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths<'a, T>(entries: impl Iterator<Item = (PathBuf, &'a T)>) -> Vec<PathBuf>
+    where
+        T: 'a,
+    {
+        entries.map(|(p, _)| p).collect()
+    }
+
+    #[test]
+    fn insert_get_and_remove() {
+        let mut index = Index::new();
+        assert_eq!(index.insert(Path::new("/a/b.txt"), 1), None);
+        assert_eq!(index.insert(Path::new("/a/b.txt/"), 2), Some(1));
+        assert_eq!(index.len(), 1);
+
+        assert_eq!(index.get(Path::new("/a/b.txt")), Some(&2));
+        assert_eq!(index.get(Path::new("/a")), None);
+        *index.get_mut(Path::new("/a/b.txt")).unwrap() += 1;
+
+        assert_eq!(index.remove(Path::new("/a")), None);
+        assert_eq!(index.remove(Path::new("/a/b.txt")), Some(3));
+        assert!(index.is_empty());
+        assert_eq!(index.root.children.len(), 0);
+    }
+
+    #[test]
+    fn fills_in_ancestors() {
+        let mut index = Index::new();
+        index.insert(Path::new("/a"), "kept".to_string());
+
+        let value = index.get_or_insert_with(Path::new("/a/b/c"), |p| p.display().to_string());
+        assert_eq!(value, "/a/b/c");
+        assert_eq!(index.get(Path::new("/")), Some(&"/".to_string()));
+        assert_eq!(index.get(Path::new("/a")), Some(&"kept".to_string()));
+        assert_eq!(index.get(Path::new("/a/b")), Some(&"/a/b".to_string()));
+        assert_eq!(index.len(), 4);
+    }
+
+    #[test]
+    fn ordered_traversal() {
+        let mut index = Index::new();
+        for path in ["/b", "/a/z", "/a.txt", "/a/c/d", "/c"] {
+            index.insert(Path::new(path), ());
+        }
+        index.insert(Path::new("/a"), ());
+
+        assert_eq!(
+            index
+                .children(Path::new("/"))
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>(),
+            ["a", "a.txt", "b", "c"]
+        );
+        assert_eq!(index.children(Path::new("/nowhere")).count(), 0);
+
+        // /a/c holds nothing itself, so it's skipped on the way to /a/c/d
+        assert_eq!(
+            paths(index.subtree(Path::new("/a"))),
+            ["/a", "/a/c/d", "/a/z"].map(PathBuf::from)
+        );
+        assert_eq!(
+            paths(index.iter()),
+            ["/a", "/a/c/d", "/a/z", "/a.txt", "/b", "/c"].map(PathBuf::from)
+        );
+    }
+}
@@ -0,0 +1,5 @@
+//! Client side of the voracious code storage protocol, and the path index the server (this
+//! crate's main binary) keeps its files in.
+
+pub mod client;
+pub mod index;
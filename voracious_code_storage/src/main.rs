@@ -1,89 +1,330 @@
+use acl::Access;
+use clap::Parser;
+use codec::{Request, VcsCodec};
+use futures_util::{SinkExt, StreamExt};
 use std::{
-    io::{BufRead, BufReader, BufWriter, Read, Write},
-    net::TcpListener,
-    thread,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
+use tokio::net::TcpStream;
+use tokio_util::codec::Framed;
 
-fn main() {
-    let listener = TcpListener::bind("0.0.0.0:8080").unwrap();
-    let repo = repo::Repo::new();
+#[derive(Parser)]
+struct Args {
+    #[command(flatten)]
+    server: protohackers_common::Config,
 
-    for stream in listener.incoming() {
-        let mut repo = repo.clone();
+    /// Directory the repository is stored in
+    #[arg(long, env = "VCS_ROOT", default_value = "./files")]
+    root: PathBuf,
 
-        thread::spawn(move || {
-            let stream = stream.unwrap();
-            let mut reader = BufReader::new(stream.try_clone().unwrap());
-            let mut writer = BufWriter::new(stream);
-            let mut buf_op = String::with_capacity(512);
-            let mut buf_dat = vec![0; 1024];
+    /// Store a file's full contents every this many revisions, and deltas in between
+    #[arg(long, env = "VCS_SNAPSHOT_INTERVAL", default_value_t = 16, value_parser = clap::value_parser!(u16).range(1..))]
+    snapshot_interval: u16,
 
-            loop {
-                writer.write_all(b"READY\n").ok();
-                writer.flush().ok();
+    /// Largest file a PUT may upload, in bytes
+    #[arg(long, env = "VCS_MAX_PUT_SIZE", default_value_t = 1024 * 1024)]
+    max_put_size: usize,
 
-                buf_op.clear();
-                if let Ok(0) = reader.read_line(&mut buf_op) {
-                    break;
+    /// Seconds a PUT body may go without receiving any data before the connection is dropped
+    #[arg(long, env = "VCS_UPLOAD_TIMEOUT", default_value_t = 30)]
+    upload_timeout: u64,
+
+    /// Limit the files under a dir, as `dir:bytes:files` with either limit left empty for none
+    #[arg(long = "quota", env = "VCS_QUOTAS", value_delimiter = ',')]
+    quotas: Vec<quota::Quota>,
+
+    /// Let connections which `AUTH` with a token read and/or write under a path prefix, as
+    /// `token:r|w|rw:prefix`. An empty token applies before `AUTH`. With none, anyone can do
+    /// anything
+    #[arg(long = "grant", env = "VCS_GRANTS", value_delimiter = ',')]
+    grants: Vec<acl::Grant>,
+
+    /// Keep each file's last this many revisions. Older ones go unless `--keep-for` keeps them
+    #[arg(long, env = "VCS_KEEP_REVISIONS", value_parser = clap::value_parser!(u64).range(1..))]
+    keep_revisions: Option<u64>,
+
+    /// Keep revisions younger than this many seconds. Older ones go unless `--keep-revisions`
+    /// keeps them
+    #[arg(long, env = "VCS_KEEP_FOR")]
+    keep_for: Option<u64>,
+
+    /// Seconds between sweeps for expired revisions, when either limit is set
+    #[arg(long, env = "VCS_COMPACT_INTERVAL", default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    compact_interval: u64,
+}
+
+/// Settings every connection shares.
+struct Settings {
+    max_put_size: usize,
+    upload_timeout: Duration,
+    acl: acl::Acl,
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let args: Args = protohackers_common::init_with();
+    let repo = repo::Repo::open(
+        &args.root,
+        repo::Options {
+            snapshot_interval: usize::from(args.snapshot_interval),
+            quotas: args.quotas,
+            retention: repo::Retention {
+                keep_last: args.keep_revisions.map(|n| n as usize),
+                keep_for: args.keep_for.map(Duration::from_secs),
+            },
+        },
+    )?;
+
+    if !repo.retention().is_unlimited() {
+        tokio::spawn(compact(
+            repo.clone(),
+            Duration::from_secs(args.compact_interval),
+        ));
+    }
+
+    let settings = Arc::new(Settings {
+        max_put_size: args.max_put_size,
+        upload_timeout: Duration::from_secs(args.upload_timeout),
+        acl: acl::Acl::new(args.grants),
+    });
+
+    protohackers_common::serve(&args.server, move |stream| {
+        handle_client(stream, repo.clone(), Arc::clone(&settings))
+    })
+    .await
+}
+
+/// Drop expired revisions every `every`, starting now.
+async fn compact(repo: repo::Repo, every: Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+
+        let repo = repo.clone();
+        match tokio::task::spawn_blocking(move || repo.compact()).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(expired)) => tracing::info!(expired, "compacted repo"),
+            Ok(Err(err)) => tracing::warn!(?err, "compaction failed"),
+            Err(err) => tracing::error!(%err, "compaction panicked"),
+        }
+    }
+}
+
+async fn handle_client(stream: TcpStream, repo: repo::Repo, settings: Arc<Settings>) {
+    let upload_timeout = settings.upload_timeout;
+    let mut framed = Framed::new(stream, VcsCodec::new(settings.max_put_size));
+    let mut mode = repo::Mode::default();
+    let mut token = String::new();
+
+    if framed.send(b"READY\n".to_vec()).await.is_err() {
+        return;
+    }
+
+    loop {
+        // Framed::next is cancel safe, so an idle connection just goes round again
+        let request = match tokio::time::timeout(upload_timeout, framed.next()).await {
+            Ok(Some(Ok(request))) => request,
+            Ok(Some(Err(err))) => {
+                tracing::warn!(%err, "bad request");
+                framed.send(b"ERR\n".to_vec()).await.ok();
+                break;
+            }
+            Ok(None) => break,
+            Err(_) if framed.codec().stalled(upload_timeout) => {
+                framed
+                    .send(error::Error::Timeout.to_string().into_bytes())
+                    .await
+                    .ok();
+                break;
+            }
+            Err(_) => continue,
+        };
+
+        let mut res = match request {
+            Request::Op(parsers::Op::Mode(new_mode)) => {
+                mode = new_mode;
+                b"OK\n".to_vec()
+            }
+            Request::Op(parsers::Op::Auth(new_token)) if settings.acl.knows(&new_token) => {
+                token = new_token;
+                b"OK\n".to_vec()
+            }
+            Request::Op(parsers::Op::Auth(_)) => error::Error::Auth.to_string().into_bytes(),
+            request
+                if !required_access(&request)
+                    .into_iter()
+                    .all(|(access, path)| settings.acl.allows(&token, access, path)) =>
+            {
+                error::Error::Denied.to_string().into_bytes()
+            }
+            request => {
+                // The repo does blocking file IO
+                let mut worker = repo.clone();
+                tokio::task::spawn_blocking(move || respond(&mut worker, mode, request))
+                    .await
+                    .unwrap_or_else(|_| error::Error::Storage.to_string().into_bytes())
+            }
+        };
+        res.extend(b"READY\n");
+
+        if framed.send(res).await.is_err() {
+            break;
+        }
+
+        tracing::debug!(files = repo.len(), "tracking");
+    }
+}
+
+/// The access a request needs, and where.
+fn required_access(request: &Request) -> Vec<(Access, &Path)> {
+    match request {
+        Request::Put(path, _) => vec![(Access::Write, path)],
+        Request::Op(op) => match op {
+            parsers::Op::Get(path, ..)
+            | parsers::Op::List(path, ..)
+            | parsers::Op::Tree(path)
+            | parsers::Op::Log(path)
+            | parsers::Op::Diff(path, ..) => vec![(Access::Read, path)],
+            parsers::Op::Delete(path) => vec![(Access::Write, path)],
+            parsers::Op::Move(from, to) => vec![(Access::Write, from), (Access::Write, to)],
+            // Tags cover the whole repo
+            parsers::Op::Tag(_) => vec![(Access::Write, Path::new("/"))],
+            parsers::Op::Put(..)
+            | parsers::Op::Mode(_)
+            | parsers::Op::Auth(_)
+            | parsers::Op::Help
+            | parsers::Op::Err(_) => vec![],
+        },
+        Request::Unknown => vec![],
+    }
+}
+
+/// Carry out a request, returning the response to send.
+fn respond(repo: &mut repo::Repo, mode: repo::Mode, request: Request) -> Vec<u8> {
+    let op = match request {
+        Request::Put(path, data) => {
+            return match repo.put(&path, &data, mode) {
+                Ok(rev) => format!("OK r{rev}\n").into_bytes(),
+                Err(err) => err.to_string().into_bytes(),
+            };
+        }
+        Request::Op(op) => op,
+        Request::Unknown => return b"ERR\n".to_vec(),
+    };
+
+    let res = match op {
+        parsers::Op::Get(path, rev, at) => {
+            match at.map_or_else(|| repo.get(&path, rev), |at| repo.get_at(&path, &at)) {
+                Ok((_rev, data)) if mode.allows(&data) => {
+                    let mut res = format!("OK {}\n", data.len()).into_bytes();
+                    res.extend(data);
+
+                    return res;
                 }
+                Ok(_) => error::Error::Binary.to_string(),
+                Err(err) => err.to_string(),
+            }
+        }
+        parsers::Op::List(path, recursive, glob, at) => {
+            let entries = match recursive {
+                true => at
+                    .as_ref()
+                    .map_or_else(|| Ok(repo.walk(&path)), |at| repo.walk_at(&path, at)),
+                false => at
+                    .as_ref()
+                    .map_or_else(|| Ok(repo.list(&path)), |at| repo.list_at(&path, at))
+                    .map(|list| list.into_iter().map(|i| (path.join(i.name()), i)).collect()),
+            };
+            let entries = match entries {
+                Ok(entries) => entries,
+                Err(err) => return err.to_string().into_bytes(),
+            };
 
-                match parsers::parse(buf_op.as_bytes()) {
-                    Ok((_, op)) => match op {
-                        parsers::Op::Put(path, len) => {
-                            buf_dat.resize(len, 0);
-                            reader.read_exact(&mut buf_dat).ok();
-
-                            let res = match repo.put(&path, &buf_dat) {
-                                Ok(rev) => format!("OK r{rev}\n"),
-                                Err(err) => err.to_string(),
-                            };
-
-                            writer.write_all(res.as_bytes()).ok();
-                        }
-                        parsers::Op::Get(path, rev) => {
-                            let res = match repo.get(&path, rev) {
-                                Ok((_rev, data)) => {
-                                    let len = data.len();
-                                    format!("OK {len}\n{data}")
-                                }
-                                Err(err) => err.to_string(),
-                            };
-
-                            writer.write_all(res.as_bytes()).ok();
-                        }
-                        parsers::Op::List(path) => {
-                            let list = repo.list(&path);
-
-                            writer
-                                .write_all(format!("OK {}\n", list.len()).as_bytes())
-                                .ok();
-
-                            for i in list {
-                                writer.write_all(i.to_string().as_bytes()).ok();
-                            }
-                        }
-                        parsers::Op::Help => {
-                            writer
-                                .write_all("OK usage: HELP|GET|PUT|LIST".as_bytes())
-                                .ok();
-                        }
-                        parsers::Op::Err(err) => {
-                            writer.write_all(err.to_string().as_bytes()).ok();
-                        }
-                    },
-                    Err(_) => {
-                        writer.write_all(b"ERR\n").ok();
-                    }
+            let list = entries
+                .into_iter()
+                .filter(|(_, i)| {
+                    glob.as_ref()
+                        .is_none_or(|g| glob::matches(g, &i.name().to_string_lossy()))
+                })
+                .collect::<Vec<_>>();
+
+            let mut res = format!("OK {}\n", list.len());
+            for (p, i) in list {
+                // Entries in subdirs are shown relative to the listed dir
+                let within = p.parent().and_then(|p| p.strip_prefix(&path).ok());
+                match within.filter(|w| !w.as_os_str().is_empty()) {
+                    Some(within) => res.push_str(&format!("{}/{i}", within.display())),
+                    None => res.push_str(&i.to_string()),
                 }
+            }
 
-                writer.flush().ok();
+            res
+        }
+        parsers::Op::Tree(path) => {
+            let tree = repo.walk(&path);
 
-                eprintln!("Tracking {} files...", repo.len())
+            let mut res = format!("OK {}\n", tree.len());
+            for (p, i) in tree {
+                let depth = p.strip_prefix(&path).map_or(1, |p| p.iter().count());
+                res.push_str(&format!("{}{i}", "  ".repeat(depth - 1)));
             }
-        });
-    }
+
+            res
+        }
+        parsers::Op::Log(path) => match repo.log(&path) {
+            Ok(log) => {
+                let mut res = format!("OK {}\n", log.len());
+                for r in log {
+                    res.push_str(&r.to_string());
+                }
+
+                res
+            }
+            Err(err) => err.to_string(),
+        },
+        parsers::Op::Diff(path, from, to) => match repo.diff(&path, from, to) {
+            Ok(diff) => {
+                let len = diff.len();
+                format!("OK {len}\n{diff}")
+            }
+            Err(err) => err.to_string(),
+        },
+        parsers::Op::Delete(path) => match repo.delete(&path) {
+            Ok(()) => "OK\n".to_string(),
+            Err(err) => err.to_string(),
+        },
+        parsers::Op::Move(from, to) => match repo.rename(&from, &to) {
+            Ok(rev) => format!("OK r{rev}\n"),
+            Err(err) => err.to_string(),
+        },
+        parsers::Op::Tag(name) => match repo.tag(&name) {
+            Ok(commit) => format!("OK c{commit}\n"),
+            Err(err) => err.to_string(),
+        },
+        parsers::Op::Help => {
+            "OK usage: HELP|GET|PUT|LIST|TREE|LOG|DIFF|DELETE|MOVE|MODE|TAG|AUTH\n".to_string()
+        }
+        // The codec reads PUT bodies itself
+        parsers::Op::Put(..) => error::Error::Put.to_string(),
+        parsers::Op::Mode(_) | parsers::Op::Auth(_) => {
+            unreachable!("the connection tracks its own mode and token")
+        }
+        parsers::Op::Err(err) => err.to_string(),
+    };
+
+    res.into_bytes()
 }
 
+mod acl;
+mod codec;
+mod delta;
 mod error;
+mod glob;
+mod manifest;
 mod parsers;
+mod quota;
 mod repo;
+mod store;
@@ -0,0 +1,117 @@
+use crate::store::BlobHash;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// One change to the repo, as stored in the manifest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum Record {
+    Put {
+        path: PathBuf,
+        rev: usize,
+        /// Hash of the revision's full contents
+        blob: BlobHash,
+        /// Blob of the delta from the previous revision, if the contents aren't stored whole
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delta: Option<BlobHash>,
+        /// Length of the full contents
+        #[serde(default)]
+        size: usize,
+        /// When the revision was stored, in seconds since the Unix epoch
+        #[serde(default)]
+        time: u64,
+    },
+    /// Tombstone the file at `path`; its revisions stay readable
+    Delete {
+        path: PathBuf,
+        #[serde(default)]
+        time: u64,
+    },
+    /// Rename the file at `from` to `to`, history and all, replacing any tombstone at `to`
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+        #[serde(default)]
+        time: u64,
+    },
+    /// Name the repo as it was after its first `commit` puts, deletes and moves
+    Tag { name: String, commit: usize },
+    /// Drop the revisions of the file at `path` from before `rev`, which is now stored whole
+    Expire { path: PathBuf, rev: usize },
+}
+
+/// Append-only log of [`Record`]s, one JSON object per line. Replaying it rebuilds the repo.
+#[derive(Debug)]
+pub struct Manifest {
+    file: File,
+}
+
+impl Manifest {
+    /// Open (or create) the manifest under `root`, returning it with every record it holds.
+    ///
+    /// A crash mid-append can leave a torn final line. That record was never acknowledged, so it
+    /// is dropped and the file truncated back to the last complete one.
+    pub fn open(root: &Path) -> io::Result<(Self, Vec<Record>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(root.join("manifest"))?;
+
+        let mut records = Vec::new();
+        let mut valid_len = 0;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+
+            if n == 0 || !line.ends_with('\n') {
+                break;
+            }
+
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) => records.push(record),
+                Err(err) => {
+                    tracing::warn!(%err, offset = valid_len, "discarding corrupt manifest tail");
+                    break;
+                }
+            }
+
+            valid_len += n as u64;
+        }
+
+        if valid_len != file.metadata()?.len() {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        Ok((Self { file }, records))
+    }
+
+    /// Durably append `record`; once this returns it will survive a crash.
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let len = self.file.metadata()?.len();
+
+        let res = self
+            .file
+            .write_all(&line)
+            .and_then(|_| self.file.sync_data());
+
+        if res.is_err() {
+            // Don't leave a partial line for the next append to be glued onto
+            self.file.set_len(len).ok();
+        }
+
+        res
+    }
+}
@@ -1,28 +1,42 @@
+use crate::repo::{Mode, Snapshot};
 use std::{path::PathBuf, str::FromStr};
 
 use nom::{
     branch::alt,
     bytes::complete::{tag_no_case, take_till, take_till1},
     character::{
-        complete::{digit1, newline, space0, space1},
+        complete::{char, digit1, newline, space0, space1},
         is_newline, is_space,
     },
-    combinator::{map_res, value, verify},
-    sequence::{delimited, preceded, Tuple},
+    combinator::{map, map_opt, map_res, opt, value, verify},
+    sequence::{delimited, preceded, terminated, Tuple},
     IResult,
 };
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Op {
     Put(PathBuf, usize),
-    Get(PathBuf, usize),
-    List(PathBuf),
+    /// File, revision and the snapshot to look in, if not the current state
+    Get(PathBuf, usize, Option<Snapshot>),
+    /// Dir, whether to recurse into subdirs, a glob entry names must match and the snapshot to
+    /// look in
+    List(PathBuf, bool, Option<String>, Option<Snapshot>),
+    Tree(PathBuf),
+    Log(PathBuf),
+    Diff(PathBuf, usize, usize),
+    Delete(PathBuf),
+    Move(PathBuf, PathBuf),
+    Mode(Mode),
+    Tag(String),
+    Auth(String),
     Help,
     Err(crate::error::Error),
 }
 
 pub fn parse(input: &[u8]) -> IResult<&[u8], Op> {
-    let (input, op) = alt((put, get, list, help, incomplete))(input)?;
+    let (input, op) = alt((
+        put, get, list, tree, log, diff, delete, mv, mode, tag, auth, help, incomplete,
+    ))(input)?;
 
     Ok((input, op))
 }
@@ -60,32 +74,219 @@ fn get(input: &[u8]) -> IResult<&[u8], Op> {
             space0,
         ),
         alt((
-            value(usize::MAX, newline),
-            preceded(
-                tag_no_case("r"),
-                map_res(digit1, |b| std::str::from_utf8(b).unwrap().parse::<usize>()),
+            value((usize::MAX, None), newline),
+            map(
+                preceded(
+                    tag_no_case("r"),
+                    map_res(digit1, |b| std::str::from_utf8(b).unwrap().parse::<usize>()),
+                ),
+                |rev| (rev, None),
             ),
+            map(terminated(snapshot, preceded(space0, newline)), |at| {
+                (usize::MAX, Some(at))
+            }),
         )),
     )
         .parse(input)?;
 
-    Ok((input, Op::Get(path, rev)))
+    let (rev, at) = rev;
+
+    Ok((input, Op::Get(path, rev, at)))
 }
 
 fn list(input: &[u8]) -> IResult<&[u8], Op> {
-    let (input, (_, path)) = (
+    let (input, (_, recursive, (path, glob), at, _)) = (
         tag_no_case("LIST"),
+        map(opt(preceded(space1, tag_no_case("-r"))), |r| r.is_some()),
+        preceded(
+            space1,
+            map_opt(take_till(is_space_or_newline), dir_and_glob),
+        ),
+        opt(preceded(space1, snapshot)),
+        preceded(space0, newline),
+    )
+        .parse(input)?;
+
+    Ok((input, Op::List(path, recursive, glob, at)))
+}
+
+fn tree(input: &[u8]) -> IResult<&[u8], Op> {
+    let (input, (_, path)) = (
+        tag_no_case("TREE"),
         delimited(
             space1,
-            map_res(verify(take_till(is_newline), is_valid_dirname), |b| {
-                PathBuf::from_str(std::str::from_utf8(b).unwrap())
+            map_res(
+                verify(take_till(is_space_or_newline), is_valid_dirname),
+                |b| PathBuf::from_str(std::str::from_utf8(b).unwrap()),
+            ),
+            preceded(space0, newline),
+        ),
+    )
+        .parse(input)?;
+
+    Ok((input, Op::Tree(path)))
+}
+
+/// Split a listing path into its dir, and a glob if the final part has any wildcards.
+fn dir_and_glob(b: &[u8]) -> Option<(PathBuf, Option<String>)> {
+    let s = std::str::from_utf8(b).ok()?;
+
+    match s.rsplit_once('/') {
+        Some((dir, glob)) if glob.contains(crate::glob::SPECIAL) => {
+            let dir = if dir.is_empty() { "/" } else { dir };
+            let valid_glob = glob
+                .chars()
+                .all(|c| crate::glob::SPECIAL.contains(&c) || !NOT_YOU.contains(&c));
+
+            (is_valid_dirname(dir.as_bytes()) && valid_glob)
+                .then(|| (PathBuf::from(dir), Some(glob.to_string())))
+        }
+        _ => is_valid_dirname(b).then(|| (PathBuf::from(s), None)),
+    }
+}
+
+fn log(input: &[u8]) -> IResult<&[u8], Op> {
+    let (input, (_, path)) = (
+        tag_no_case("LOG"),
+        delimited(
+            space1,
+            map_res(
+                verify(take_till(is_space_or_newline), is_valid_filename),
+                |b| PathBuf::from_str(std::str::from_utf8(b).unwrap()),
+            ),
+            preceded(space0, newline),
+        ),
+    )
+        .parse(input)?;
+
+    Ok((input, Op::Log(path)))
+}
+
+fn diff(input: &[u8]) -> IResult<&[u8], Op> {
+    let (input, (_, path, from, to, _)) = (
+        tag_no_case("DIFF"),
+        delimited(
+            space1,
+            map_res(
+                verify(take_till(is_space_or_newline), is_valid_filename),
+                |b| PathBuf::from_str(std::str::from_utf8(b).unwrap()),
+            ),
+            space1,
+        ),
+        rev,
+        preceded(space1, rev),
+        preceded(space0, newline),
+    )
+        .parse(input)?;
+
+    Ok((input, Op::Diff(path, from, to)))
+}
+
+fn delete(input: &[u8]) -> IResult<&[u8], Op> {
+    let (input, (_, path)) = (
+        tag_no_case("DELETE"),
+        delimited(space1, file, preceded(space0, newline)),
+    )
+        .parse(input)?;
+
+    Ok((input, Op::Delete(path)))
+}
+
+fn mv(input: &[u8]) -> IResult<&[u8], Op> {
+    let (input, (_, from, to, _)) = (
+        tag_no_case("MOVE"),
+        preceded(space1, file),
+        preceded(space1, file),
+        preceded(space0, newline),
+    )
+        .parse(input)?;
+
+    Ok((input, Op::Move(from, to)))
+}
+
+fn mode(input: &[u8]) -> IResult<&[u8], Op> {
+    let (input, (_, mode, _)) = (
+        tag_no_case("MODE"),
+        preceded(
+            space1,
+            alt((
+                value(Mode::Text, tag_no_case("TEXT")),
+                value(Mode::Binary, tag_no_case("BINARY")),
+            )),
+        ),
+        preceded(space0, newline),
+    )
+        .parse(input)?;
+
+    Ok((input, Op::Mode(mode)))
+}
+
+fn tag(input: &[u8]) -> IResult<&[u8], Op> {
+    let (input, (_, name)) = (
+        tag_no_case("TAG"),
+        delimited(
+            space1,
+            map_opt(take_till(is_space_or_newline), tag_name),
+            preceded(space0, newline),
+        ),
+    )
+        .parse(input)?;
+
+    Ok((input, Op::Tag(name)))
+}
+
+fn auth(input: &[u8]) -> IResult<&[u8], Op> {
+    let (input, (_, token)) = (
+        tag_no_case("AUTH"),
+        delimited(
+            space1,
+            map_res(take_till1(is_space_or_newline), |b| {
+                std::str::from_utf8(b).map(str::to_string)
             }),
-            newline,
+            preceded(space0, newline),
         ),
     )
         .parse(input)?;
 
-    Ok((input, Op::List(path)))
+    Ok((input, Op::Auth(token)))
+}
+
+/// `@name` for a tag, or `@secs` for the repo as it was that many seconds after the Unix epoch.
+fn snapshot(input: &[u8]) -> IResult<&[u8], Snapshot> {
+    preceded(
+        char('@'),
+        map_opt(
+            take_till1(is_space_or_newline),
+            |b| match std::str::from_utf8(b).ok()?.parse::<u64>() {
+                Ok(time) => Some(Snapshot::Time(time)),
+                Err(_) => tag_name(b).map(Snapshot::Tag),
+            },
+        ),
+    )(input)
+}
+
+/// Tag names start with a letter, then may have digits, `-`, `_` and `.`.
+fn tag_name(b: &[u8]) -> Option<String> {
+    let s = std::str::from_utf8(b).ok()?;
+
+    (s.starts_with(|c: char| c.is_ascii_alphabetic())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)))
+    .then(|| s.to_string())
+}
+
+fn file(input: &[u8]) -> IResult<&[u8], PathBuf> {
+    map_res(
+        verify(take_till(is_space_or_newline), is_valid_filename),
+        |b| PathBuf::from_str(std::str::from_utf8(b).unwrap()),
+    )(input)
+}
+
+fn rev(input: &[u8]) -> IResult<&[u8], usize> {
+    preceded(
+        opt(tag_no_case("r")),
+        map_res(digit1, |b| std::str::from_utf8(b).unwrap().parse::<usize>()),
+    )(input)
 }
 
 fn help(input: &[u8]) -> IResult<&[u8], Op> {
@@ -99,6 +300,14 @@ fn incomplete(input: &[u8]) -> IResult<&[u8], Op> {
         value(Op::Err(crate::error::Error::Put), tag_no_case("PUT")),
         value(Op::Err(crate::error::Error::Get), tag_no_case("GET")),
         value(Op::Err(crate::error::Error::List), tag_no_case("LIST")),
+        value(Op::Err(crate::error::Error::Tree), tag_no_case("TREE")),
+        value(Op::Err(crate::error::Error::Log), tag_no_case("LOG")),
+        value(Op::Err(crate::error::Error::Diff), tag_no_case("DIFF")),
+        value(Op::Err(crate::error::Error::Delete), tag_no_case("DELETE")),
+        value(Op::Err(crate::error::Error::Move), tag_no_case("MOVE")),
+        value(Op::Err(crate::error::Error::Mode), tag_no_case("MODE")),
+        value(Op::Err(crate::error::Error::Tag), tag_no_case("TAG")),
+        value(Op::Err(crate::error::Error::Auth), tag_no_case("AUTH")),
     )))(input)
 }
 
@@ -107,6 +316,10 @@ static NOT_YOU: [char; 24] = [
     '$', '"', '!', '^', '`',
 ];
 
+fn is_space_or_newline(b: u8) -> bool {
+    is_space(b) || is_newline(b)
+}
+
 fn is_valid_filename(b: &[u8]) -> bool {
     let s = std::str::from_utf8(b).unwrap();
     !s.contains(NOT_YOU) && !s.contains("//") && !s.ends_with('/') && s.starts_with('/')
@@ -116,3 +329,162 @@ fn is_valid_dirname(b: &[u8]) -> bool {
     let s = std::str::from_utf8(b).unwrap();
     !s.contains(NOT_YOU) && !s.contains("//") && s.starts_with('/')
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log() {
+        assert_eq!(
+            parse(b"LOG /a/b.txt\n"),
+            Ok((b"".as_ref(), Op::Log(PathBuf::from("/a/b.txt"))))
+        );
+        assert_eq!(
+            parse(b"log /a/\n"),
+            Ok((b" /a/\n".as_ref(), Op::Err(crate::error::Error::Log)))
+        );
+    }
+
+    #[test]
+    fn diff() {
+        assert_eq!(
+            parse(b"DIFF /a.txt r1 r3\n"),
+            Ok((b"".as_ref(), Op::Diff(PathBuf::from("/a.txt"), 1, 3)))
+        );
+        assert_eq!(
+            parse(b"diff /a.txt 2 1\n"),
+            Ok((b"".as_ref(), Op::Diff(PathBuf::from("/a.txt"), 2, 1)))
+        );
+        assert_eq!(
+            parse(b"DIFF /a.txt r1\n"),
+            Ok((b" /a.txt r1\n".as_ref(), Op::Err(crate::error::Error::Diff)))
+        );
+    }
+
+    #[test]
+    fn delete_and_move() {
+        assert_eq!(
+            parse(b"DELETE /a/b.txt\n"),
+            Ok((b"".as_ref(), Op::Delete(PathBuf::from("/a/b.txt"))))
+        );
+        assert_eq!(
+            parse(b"move /a.txt /b/c.txt\n"),
+            Ok((
+                b"".as_ref(),
+                Op::Move(PathBuf::from("/a.txt"), PathBuf::from("/b/c.txt"))
+            ))
+        );
+        assert_eq!(
+            parse(b"MOVE /a.txt /b/\n"),
+            Ok((
+                b" /a.txt /b/\n".as_ref(),
+                Op::Err(crate::error::Error::Move)
+            ))
+        );
+    }
+
+    #[test]
+    fn mode() {
+        assert_eq!(
+            parse(b"MODE BINARY\n"),
+            Ok((b"".as_ref(), Op::Mode(Mode::Binary)))
+        );
+        assert_eq!(
+            parse(b"mode text\n"),
+            Ok((b"".as_ref(), Op::Mode(Mode::Text)))
+        );
+        assert_eq!(
+            parse(b"MODE HEX\n"),
+            Ok((b" HEX\n".as_ref(), Op::Err(crate::error::Error::Mode)))
+        );
+    }
+
+    #[test]
+    fn list() {
+        assert_eq!(
+            parse(b"LIST /a/\n"),
+            Ok((
+                b"".as_ref(),
+                Op::List(PathBuf::from("/a/"), false, None, None)
+            ))
+        );
+        assert_eq!(
+            parse(b"list -r /\n"),
+            Ok((b"".as_ref(), Op::List(PathBuf::from("/"), true, None, None)))
+        );
+        assert_eq!(
+            parse(b"LIST /src/*.rs\n"),
+            Ok((
+                b"".as_ref(),
+                Op::List(PathBuf::from("/src"), false, Some("*.rs".into()), None)
+            ))
+        );
+        assert_eq!(
+            parse(b"LIST -r /a?c\n"),
+            Ok((
+                b"".as_ref(),
+                Op::List(PathBuf::from("/"), true, Some("a?c".into()), None)
+            ))
+        );
+        assert_eq!(
+            parse(b"LIST /*/b.rs\n"),
+            Ok((b" /*/b.rs\n".as_ref(), Op::Err(crate::error::Error::List)))
+        );
+        assert_eq!(
+            parse(b"TREE /src\n"),
+            Ok((b"".as_ref(), Op::Tree(PathBuf::from("/src"))))
+        );
+    }
+
+    #[test]
+    fn snapshots() {
+        assert_eq!(
+            parse(b"TAG release-1.0\n"),
+            Ok((b"".as_ref(), Op::Tag("release-1.0".into())))
+        );
+        assert_eq!(
+            parse(b"TAG 1.0\n"),
+            Ok((b" 1.0\n".as_ref(), Op::Err(crate::error::Error::Tag)))
+        );
+        assert_eq!(
+            parse(b"GET /a.txt @v1\n"),
+            Ok((
+                b"".as_ref(),
+                Op::Get(
+                    PathBuf::from("/a.txt"),
+                    usize::MAX,
+                    Some(Snapshot::Tag("v1".into()))
+                )
+            ))
+        );
+        assert_eq!(
+            parse(b"LIST -r /src @1700000000\n"),
+            Ok((
+                b"".as_ref(),
+                Op::List(
+                    PathBuf::from("/src"),
+                    true,
+                    None,
+                    Some(Snapshot::Time(1_700_000_000))
+                )
+            ))
+        );
+        assert_eq!(
+            parse(b"GET /a.txt @-\n"),
+            Ok((b" /a.txt @-\n".as_ref(), Op::Err(crate::error::Error::Get)))
+        );
+    }
+
+    #[test]
+    fn auth() {
+        assert_eq!(
+            parse(b"AUTH s3cret\n"),
+            Ok((b"".as_ref(), Op::Auth("s3cret".into())))
+        );
+        assert_eq!(
+            parse(b"auth\n"),
+            Ok((b"\n".as_ref(), Op::Err(crate::error::Error::Auth)))
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use std::{path::PathBuf, str::FromStr};
+
+/// Limits on the live files under `dir`, counting the latest revision of each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quota {
+    pub dir: PathBuf,
+    pub max_bytes: Option<usize>,
+    pub max_files: Option<usize>,
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    /// Parse `dir:bytes:files`, where either limit may be left empty for none.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.rsplitn(3, ':');
+        let (Some(files), Some(bytes), Some(dir)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("expected dir:bytes:files, got {s}"));
+        };
+
+        if !dir.starts_with('/') {
+            return Err(format!("dir must be an absolute path, got {dir}"));
+        }
+
+        let limit = |n: &str| match n {
+            "" => Ok(None),
+            n => n
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("not a number: {n}")),
+        };
+
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            max_bytes: limit(bytes)?,
+            max_files: limit(files)?,
+        })
+    }
+}
+
+/// How much of a [`Quota`] the live files under its dir use, kept up to date as they change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub bytes: usize,
+    pub files: usize,
+}
+
+impl Usage {
+    /// Swap a file of `before` bytes for one of `after`, where `None` is no file at all.
+    pub fn replace(&mut self, before: Option<usize>, after: Option<usize>) {
+        if let Some(size) = before {
+            self.bytes -= size;
+            self.files -= 1;
+        }
+
+        if let Some(size) = after {
+            self.bytes += size;
+            self.files += 1;
+        }
+    }
+
+    /// Whether this is within `quota`.
+    pub fn within(&self, quota: &Quota) -> bool {
+        quota.max_bytes.is_none_or(|max| self.bytes <= max)
+            && quota.max_files.is_none_or(|max| self.files <= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "/src:1000:".parse(),
+            Ok(Quota {
+                dir: PathBuf::from("/src"),
+                max_bytes: Some(1000),
+                max_files: None,
+            })
+        );
+        assert_eq!(
+            "/::10".parse(),
+            Ok(Quota {
+                dir: PathBuf::from("/"),
+                max_bytes: None,
+                max_files: Some(10),
+            })
+        );
+        assert!("/src:lots:".parse::<Quota>().is_err());
+        assert!("src:1:1".parse::<Quota>().is_err());
+        assert!("/src:1".parse::<Quota>().is_err());
+    }
+}
@@ -1,26 +1,57 @@
+use crate::{
+    delta,
+    manifest::{Manifest, Record},
+    quota::{Quota, Usage},
+    store::{BlobHash, BlobStore},
+};
 use std::{
     collections::HashMap,
     ffi::{OsStr, OsString},
     fmt::Display,
-    fs,
+    io,
     path::{Path, PathBuf},
     sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use uuid::Uuid;
+use voracious_code_storage::index::Index;
+
+/// One stored version of a file.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Revision {
+    pub rev: usize,
+    /// Hash of the full contents, which is also their blob if they're stored whole
+    pub content: BlobHash,
+    /// Blob holding the changes from the previous revision, when not stored whole
+    pub delta: Option<BlobHash>,
+    pub size: usize,
+    /// Seconds since the Unix epoch
+    pub time: u64,
+}
+
+impl Revision {
+    /// The blob this revision keeps a reference to.
+    fn blob(&self) -> BlobHash {
+        self.delta.unwrap_or(self.content)
+    }
+}
+
+impl Display for Revision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "r{} {} {}", self.rev, self.size, self.time)
+    }
+}
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum INode {
-    File(OsString, Vec<(usize, Uuid)>),
+    File(OsString, Vec<Revision>),
+    /// A deleted file, whose revisions can still be read by number. A put brings it back.
+    Deleted(OsString, Vec<Revision>),
     Dir(OsString),
 }
 
 impl INode {
     pub fn new_file(path: &Path) -> Self {
-        let node_name = path.file_name().unwrap().to_os_string();
-        let filename = Uuid::new_v4();
-        fs::write(format!("{REPO_BASE}/{}", filename.as_simple()), b"").ok();
-
-        Self::File(node_name, vec![(0, filename)])
+        Self::File(path.file_name().unwrap().to_os_string(), Vec::new())
     }
 
     pub fn new_dir(path: &Path) -> Self {
@@ -31,28 +62,23 @@ impl INode {
         )
     }
 
-    pub fn update(&mut self, data: &[u8]) -> usize {
-        match self {
-            INode::File(_, history) => {
-                let (prev_rev, prev_filename) = history.last().expect("always initialised");
-
-                let prev_data =
-                    fs::read(format!("{REPO_BASE}/{}", prev_filename.as_simple())).unwrap();
-                if data == prev_data {
-                    return *prev_rev;
-                }
-
-                let curr_rev = prev_rev + 1;
-                let curr_filename = Uuid::new_v4();
-
-                fs::write(format!("{REPO_BASE}/{}", curr_filename.as_simple()), data).ok();
-                history.push((prev_rev + 1, curr_filename));
+    pub fn push(&mut self, revision: Revision) {
+        if let INode::Deleted(name, history) = self {
+            *self = INode::File(std::mem::take(name), std::mem::take(history));
+        }
 
-                curr_rev
-            }
+        match self {
+            INode::File(_, history) => history.push(revision),
+            INode::Deleted(..) => unreachable!(),
             INode::Dir(_) => panic!("cannot update a dir node"),
         }
     }
+
+    pub fn name(&self) -> &OsStr {
+        match self {
+            INode::File(name, _) | INode::Deleted(name, _) | INode::Dir(name) => name,
+        }
+    }
 }
 
 impl Display for INode {
@@ -62,9 +88,10 @@ impl Display for INode {
                 format!(
                     "{} r{}\n",
                     name.to_string_lossy(),
-                    history.last().unwrap().0
+                    history.last().unwrap().rev
                 )
             }
+            INode::Deleted(name, _) => format!("{} DELETED\n", name.to_string_lossy()),
             INode::Dir(name) => format!("{}/ DIR\n", name.to_string_lossy()),
         };
 
@@ -74,131 +101,1512 @@ impl Display for INode {
 
 impl PartialOrd for INode {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        let l = match self {
-            INode::File(n, _) => n,
-            INode::Dir(n) => n,
-        };
-
-        let r = match other {
-            INode::File(n, _) => n,
-            INode::Dir(n) => n,
-        };
-
-        Some(l.cmp(r))
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for INode {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.partial_cmp(other).unwrap()
+        self.name().cmp(other.name())
     }
 }
 
-static REPO_BASE: &str = "./files";
+/// What contents a connection may PUT and GET. Text is the default, binary allows anything.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Text,
+    Binary,
+}
+
+impl Mode {
+    /// Whether `data` may be stored or returned in this mode. Text must be UTF-8 with no control
+    /// characters other than tabs and newlines.
+    pub fn allows(&self, data: &[u8]) -> bool {
+        match self {
+            Mode::Text => std::str::from_utf8(data).is_ok_and(|s| {
+                !s.contains(|c: char| ![9, 10].contains(&(c as u8)) && c.is_control())
+            }),
+            Mode::Binary => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Every this many revisions a file's full contents are stored, rather than a delta from the
+    /// one before. Bounds how many deltas a read has to apply.
+    pub snapshot_interval: usize,
+    /// Limits on the files under particular dirs, checked whenever one is put or moved there
+    pub quotas: Vec<Quota>,
+    /// Which old revisions [`Repo::compact`] keeps
+    pub retention: Retention,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            snapshot_interval: 16,
+            quotas: Vec::new(),
+            retention: Retention::default(),
+        }
+    }
+}
+
+/// How long old revisions are kept. A revision is kept if either limit says so, and with neither
+/// set everything is kept. A live file always keeps its latest revision.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    /// Keep each file's last this many revisions
+    pub keep_last: Option<usize>,
+    /// Keep revisions younger than this
+    pub keep_for: Option<Duration>,
+}
+
+impl Retention {
+    pub fn is_unlimited(&self) -> bool {
+        self.keep_last.is_none() && self.keep_for.is_none()
+    }
+
+    /// How many of the oldest revisions in `history` have expired at `now`.
+    fn expired(&self, history: &[Revision], now: u64) -> usize {
+        let by_count = self.keep_last.map(|n| history.len().saturating_sub(n));
+        let by_age = self
+            .keep_for
+            .map(|age| history.partition_point(|r| r.time.saturating_add(age.as_secs()) <= now));
+
+        by_count.into_iter().chain(by_age).min().unwrap_or(0)
+    }
+}
+
+/// A past state of the whole repo: the one a tag was made at, or as it was at a time (in seconds
+/// since the Unix epoch).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Snapshot {
+    Tag(String),
+    Time(u64),
+}
+
+/// A change to the repo's files. Its commit number is its position in [`State::commits`], from 1.
+#[derive(Debug, Clone)]
+struct Commit {
+    time: u64,
+    change: Change,
+}
+
+#[derive(Debug, Clone)]
+enum Change {
+    Put(PathBuf, Revision),
+    Delete(PathBuf),
+    Move(PathBuf, PathBuf),
+}
+
+#[derive(Debug)]
+struct State {
+    nodes: Index<INode>,
+    store: BlobStore,
+    manifest: Manifest,
+    options: Options,
+    commits: Vec<Commit>,
+    /// The number of commits made when each tag was taken
+    tags: HashMap<String, usize>,
+    /// The revision a put starts from at each path whose tombstone expired, so numbers aren't
+    /// reused for different contents
+    next_revs: HashMap<PathBuf, usize>,
+    /// What's used of each of [`Options::quotas`], in the same order
+    usage: Vec<Usage>,
+}
+
+impl State {
+    /// The file node at `path`, creating it and any missing parent dirs.
+    fn file_mut(&mut self, path: &Path) -> &mut INode {
+        file_entry(&mut self.nodes, path)
+    }
+
+    /// Add `revision` to the history of the file at `path`, bringing it back if it was deleted.
+    fn push(&mut self, path: &Path, revision: Revision) {
+        let before = live_size(&self.nodes, path);
+
+        self.next_revs.remove(path);
+        self.file_mut(path).push(revision);
+        self.account(path, before);
+    }
+
+    /// The live file at `path` was `before` bytes, so update the usage of the quotas over it to
+    /// whatever's there now.
+    fn account(&mut self, path: &Path, before: Option<usize>) {
+        let after = live_size(&self.nodes, path);
+
+        for (quota, usage) in self.options.quotas.iter().zip(&mut self.usage) {
+            if path.starts_with(&quota.dir) {
+                usage.replace(before, after);
+            }
+        }
+    }
+
+    /// Rebuild state from a manifest record while opening the repo.
+    fn replay(&mut self, record: Record) {
+        match record {
+            Record::Put {
+                path,
+                rev,
+                blob,
+                delta,
+                size,
+                time,
+            } => {
+                let revision = Revision {
+                    rev,
+                    content: blob,
+                    delta,
+                    size,
+                    time,
+                };
+
+                self.store.retain(revision.blob());
+                self.push(&path, revision.clone());
+                self.commit(time, Change::Put(path, revision));
+            }
+            Record::Delete { path, time } => {
+                self.tombstone(&path);
+                self.commit(time, Change::Delete(path));
+            }
+            Record::Move { from, to, time } => {
+                for revision in self.relocate(&from, &to) {
+                    self.store.forget(&revision.blob());
+                }
+                self.commit(time, Change::Move(from, to));
+            }
+            Record::Tag { name, commit } => {
+                self.tags.insert(name, commit);
+            }
+            Record::Expire { path, rev } => {
+                for blob in self.expire(&path, rev) {
+                    self.store.forget(&blob);
+                }
+            }
+        }
+    }
+
+    fn commit(&mut self, time: u64, change: Change) {
+        // Keep commit times in order even if the clock goes backwards
+        let time = self.commits.last().map_or(time, |last| last.time.max(time));
+
+        self.commits.push(Commit { time, change });
+    }
+
+    /// The number of commits `at` refers to.
+    fn resolve(&self, at: &Snapshot) -> Result<usize, crate::error::Error> {
+        match at {
+            Snapshot::Tag(name) => self
+                .tags
+                .get(name)
+                .copied()
+                .ok_or(crate::error::Error::NoSuchTag),
+            Snapshot::Time(time) => Ok(self.commits.partition_point(|c| c.time <= *time)),
+        }
+    }
+
+    /// The live files as they were after the first `commit` commits, with the latest revision of
+    /// each then. Files are tagged with a lineage id which follows them through moves, paired with
+    /// where each lineage is now so its history can be found.
+    fn lineage(
+        &self,
+        commit: usize,
+    ) -> (HashMap<PathBuf, (usize, Revision)>, HashMap<usize, PathBuf>) {
+        let mut lineages = HashMap::new();
+        let mut files = HashMap::new();
+
+        for (n, c) in self.commits.iter().enumerate() {
+            let past = n < commit;
+
+            match &c.change {
+                Change::Put(path, revision) => {
+                    let next = lineages.len();
+                    let id = *lineages.entry(path.clone()).or_insert(next);
+
+                    if past {
+                        files.insert(path.clone(), (id, revision.clone()));
+                    }
+                }
+                Change::Delete(path) => {
+                    if past {
+                        files.remove(path);
+                    }
+                }
+                Change::Move(from, to) => {
+                    if let Some(id) = lineages.remove(from) {
+                        lineages.insert(to.clone(), id);
+                    }
+
+                    if past {
+                        if let Some(file) = files.remove(from) {
+                            files.insert(to.clone(), file);
+                        }
+                    }
+                }
+            }
+        }
+
+        (files, lineages.into_iter().map(|(p, id)| (id, p)).collect())
+    }
+
+    /// The tree of live files after the first `commit` commits, each with just the revision it
+    /// was at.
+    fn view(&self, commit: usize) -> Index<INode> {
+        let mut nodes = Index::new();
+
+        for (path, (_, revision)) in self.lineage(commit).0 {
+            file_entry(&mut nodes, &path).push(revision);
+        }
+
+        nodes
+    }
+
+    /// Turn the file at `path` into a tombstone.
+    fn tombstone(&mut self, path: &Path) {
+        let before = live_size(&self.nodes, path);
+
+        if let Some(INode::File(name, history)) = self.nodes.get_mut(path) {
+            let deleted = INode::Deleted(std::mem::take(name), std::mem::take(history));
+            self.nodes.insert(path, deleted);
+        }
+
+        self.account(path, before);
+        self.prune(path);
+    }
+
+    /// Move the file at `from` to `to`, returning the history of any tombstone it replaced so the
+    /// caller can drop its blobs.
+    fn relocate(&mut self, from: &Path, to: &Path) -> Vec<Revision> {
+        let before = (live_size(&self.nodes, from), live_size(&self.nodes, to));
+
+        let Some(INode::File(_, history)) = self.nodes.remove(from) else {
+            return Vec::new();
+        };
+
+        let replaced = match self.nodes.remove(to) {
+            Some(INode::Deleted(_, replaced)) => replaced,
+            _ => Vec::new(),
+        };
+
+        *self.file_mut(to) = INode::File(to.file_name().unwrap().to_os_string(), history);
+        self.next_revs.remove(to);
+        self.account(from, before.0);
+        self.account(to, before.1);
+        self.prune(from);
+
+        replaced
+    }
+
+    /// Drop the revisions of the file at `path` from before `rev`, taking a reference to the full
+    /// contents of the first one left if it was a delta. Returns the blobs which lost a reference.
+    /// A tombstone with no revisions left goes altogether, leaving just the revision to carry on
+    /// from.
+    fn expire(&mut self, path: &Path, rev: usize) -> Vec<BlobHash> {
+        let before = live_size(&self.nodes, path);
+
+        let Some(INode::File(_, history) | INode::Deleted(_, history)) = self.nodes.get_mut(path)
+        else {
+            return Vec::new();
+        };
+
+        let cut = history.partition_point(|r| r.rev < rev);
+        let mut dropped = history.drain(..cut).map(|r| r.blob()).collect::<Vec<_>>();
+
+        // The revisions its delta builds on are gone, so it has to stand alone
+        if let Some(first) = history.first_mut().filter(|_| cut > 0) {
+            if let Some(delta) = first.delta.take() {
+                self.store.retain(first.content);
+                dropped.push(delta);
+            }
+        }
+
+        if history.is_empty() {
+            self.nodes.remove(path);
+            self.next_revs.insert(path.to_path_buf(), rev);
+        }
+
+        self.account(path, before);
+
+        dropped
+    }
+
+    /// Remove the dirs above `path` which no longer hold any live files.
+    fn prune(&mut self, path: &Path) {
+        for dir in path.ancestors().skip(1) {
+            let in_use = self
+                .nodes
+                .subtree(dir)
+                .any(|(_, node)| matches!(node, INode::File(..)));
+
+            if in_use {
+                break;
+            }
+
+            if let Some(INode::Dir(_)) = self.nodes.get(dir) {
+                self.nodes.remove(dir);
+            }
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
-pub struct Repo(Arc<RwLock<HashMap<PathBuf, INode>>>);
+pub struct Repo(Arc<RwLock<State>>);
 
 impl Repo {
     pub fn len(&self) -> usize {
-        self.0.read().unwrap().len()
+        self.0.read().unwrap().nodes.len()
     }
 
-    pub fn new() -> Self {
-        fs::remove_dir_all(REPO_BASE).ok();
-        fs::create_dir_all(REPO_BASE).ok();
+    /// Open the repo stored under `root`, creating it if needed and replaying its manifest.
+    pub fn open(root: &Path, options: Options) -> io::Result<Self> {
+        std::fs::create_dir_all(root)?;
 
-        Self(Arc::new(RwLock::new(HashMap::new())))
-    }
+        let store = BlobStore::open(root)?;
+        let (manifest, records) = Manifest::open(root)?;
 
-    pub fn put(&mut self, path: &Path, data: &[u8]) -> Result<usize, crate::error::Error> {
-        let chk_data = std::str::from_utf8(data);
+        let mut state = State {
+            nodes: Index::new(),
+            store,
+            manifest,
+            usage: vec![Usage::default(); options.quotas.len()],
+            options,
+            commits: Vec::new(),
+            tags: HashMap::new(),
+            next_revs: HashMap::new(),
+        };
 
-        if chk_data.is_err()
-            || chk_data
-                .unwrap()
-                .contains(|c: char| ![9, 10].contains(&(c as u8)) && c.is_control())
-        {
+        for record in records {
+            state.replay(record);
+        }
+
+        let swept = state.store.sweep()?;
+        if swept > 0 {
+            tracing::warn!(swept, "removed unreferenced blobs");
+        }
+
+        tracing::info!(root = %root.display(), nodes = state.nodes.len(), "opened repo");
+
+        Ok(Self(Arc::new(RwLock::new(state))))
+    }
+
+    pub fn put(
+        &mut self,
+        path: &Path,
+        data: &[u8],
+        mode: Mode,
+    ) -> Result<usize, crate::error::Error> {
+        if !mode.allows(data) {
             return Err(crate::error::Error::Put);
         }
 
-        let mut parts = path.iter().fold(
-            Vec::with_capacity(path.components().count()),
-            |mut acc, el| {
-                let pre = acc.last().cloned().unwrap_or(PathBuf::new());
+        let mut lock = self.0.write().unwrap();
+        let State {
+            nodes,
+            store,
+            manifest,
+            options,
+            next_revs,
+            usage,
+            ..
+        } = &mut *lock;
 
-                acc.push(
-                    PathBuf::from(OsString::from(format!(
-                        "{}/{}",
-                        pre.to_string_lossy(),
-                        el.to_string_lossy()
-                    )))
-                    .components()
-                    .collect(),
-                );
+        let content = BlobHash::of(data);
 
-                acc
-            },
+        let (history, deleted) = match nodes.get(path) {
+            Some(INode::File(_, history)) => (history.as_slice(), false),
+            Some(INode::Deleted(_, history)) => (history.as_slice(), true),
+            Some(INode::Dir(_)) => return Err(crate::error::Error::Put),
+            None => (&[][..], false),
+        };
+
+        // A deleted file always gets a new revision, even with unchanged contents, to revive it
+        if let Some(last) = history
+            .last()
+            .filter(|last| !deleted && last.content == content)
+        {
+            return Ok(last.rev);
+        }
+
+        check_quotas(&options.quotas, usage, nodes, path, data.len(), None)?;
+
+        let rev = history.last().map_or_else(
+            || next_revs.get(path).copied().unwrap_or(1),
+            |last| last.rev + 1,
         );
+        let chain = history
+            .iter()
+            .rev()
+            .take_while(|r| r.delta.is_some())
+            .count();
 
-        let mut lock = self.0.write().unwrap();
+        // Store a delta unless it's time for a snapshot, or these contents are already stored whole
+        let delta = match history.len() {
+            len if len > 0
+                && chain + 1 < options.snapshot_interval
+                && !store.contains(&content) =>
+            {
+                let base = read_rev(store, history, len - 1).map_err(storage_err)?;
+                Some(delta::encode(&base, data)).filter(|delta| delta.len() < data.len())
+            }
+            _ => None,
+        };
 
-        let tail = parts.pop().unwrap();
+        // Blob first, then the manifest entry which makes it live
+        let delta = match delta {
+            Some(delta) => Some(store.write(&delta).map_err(storage_err)?),
+            None => {
+                store.write(data).map_err(storage_err)?;
+                None
+            }
+        };
+
+        let revision = Revision {
+            rev,
+            content,
+            delta,
+            size: data.len(),
+            time: now(),
+        };
 
-        for part in &parts {
-            lock.entry(PathBuf::from(part))
-                .or_insert_with_key(|k| INode::new_dir(k));
+        let record = Record::Put {
+            path: path.to_path_buf(),
+            rev,
+            blob: revision.content,
+            delta: revision.delta,
+            size: revision.size,
+            time: revision.time,
+        };
+        if let Err(err) = manifest.append(&record) {
+            store.release(&revision.blob()).ok();
+            return Err(storage_err(err));
         }
 
-        let rev = lock
-            .entry(PathBuf::from(&tail))
-            .or_insert_with_key(|k| INode::new_file(k))
-            .update(data);
+        // The blob reference was taken by the write, so don't retain it again
+        lock.push(path, revision.clone());
+        lock.commit(revision.time, Change::Put(path.to_path_buf(), revision));
 
         Ok(rev)
     }
 
-    pub fn get(&self, path: &Path, rev: usize) -> Result<(usize, String), crate::error::Error> {
-        let lock = self.0.read().unwrap();
-        let inode = lock.get(path).ok_or(crate::error::Error::Get)?;
-
-        match inode {
-            INode::File(_, history) => {
-                let (rev, filename) = match rev {
-                    usize::MAX => history.last(),
-                    0 => None,
-                    _ => history.iter().find(|(rev_r, _)| rev == *rev_r),
+    pub fn retention(&self) -> Retention {
+        self.0.read().unwrap().options.retention.clone()
+    }
+
+    /// Drop the revisions which have expired under the retention policy, deleting blobs nothing
+    /// else needs, and return how many went. Blobs are only ever deleted under the write lock,
+    /// which a read holds for as long as it's reading, so a `get` never loses a blob mid-way.
+    /// Files are done one at a time so reads aren't held up for long.
+    pub fn compact(&self) -> Result<usize, crate::error::Error> {
+        let paths = self
+            .0
+            .read()
+            .unwrap()
+            .nodes
+            .iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        let now = now();
+        let mut expired = 0;
+
+        for path in paths {
+            let mut lock = self.0.write().unwrap();
+
+            let (history, live) = match lock.nodes.get(&path) {
+                Some(INode::File(_, history)) => (history, true),
+                Some(INode::Deleted(_, history)) => (history, false),
+                _ => continue,
+            };
+
+            let mut cut = lock.options.retention.expired(history, now);
+            if live {
+                cut = cut.min(history.len() - 1);
+            }
+            if cut == 0 {
+                continue;
+            }
+
+            // The first revision kept, which must be stored whole before the manifest says so
+            let (rev, rebased) = match history.get(cut) {
+                Some(first) if first.delta.is_some() => {
+                    let data = read_rev(&lock.store, history, cut).map_err(storage_err)?;
+                    (first.rev, Some(data))
+                }
+                Some(first) => (first.rev, None),
+                None => (history[cut - 1].rev + 1, None),
+            };
+
+            if let Some(data) = rebased {
+                lock.store.save(&data).map_err(storage_err)?;
+            }
+
+            lock.manifest
+                .append(&Record::Expire {
+                    path: path.clone(),
+                    rev,
+                })
+                .map_err(storage_err)?;
+
+            for blob in lock.expire(&path, rev) {
+                if let Err(err) = lock.store.release(&blob) {
+                    tracing::warn!(%err, "failed to remove expired blob");
                 }
-                .ok_or(crate::error::Error::Get)?;
+            }
+
+            expired += cut;
+        }
+
+        Ok(expired)
+    }
+
+    /// The contents of revision `rev` of the file at `path`, or its latest for `usize::MAX`.
+    pub fn get(&self, path: &Path, rev: usize) -> Result<(usize, Vec<u8>), crate::error::Error> {
+        let lock = self.0.read().unwrap();
+
+        let history = match lock.nodes.get(path) {
+            Some(INode::File(_, history)) => history,
+            // A deleted file has no latest revision, but its old ones can be asked for by number
+            Some(INode::Deleted(_, history)) if rev != usize::MAX => history,
+            _ => return Err(crate::error::Error::Get),
+        };
+
+        let idx = match rev {
+            usize::MAX => history.len().checked_sub(1),
+            0 => None,
+            _ => history.iter().position(|r| r.rev == rev),
+        }
+        .ok_or(crate::error::Error::Get)?;
+
+        let data = read_rev(&lock.store, history, idx).map_err(storage_err)?;
+
+        Ok((history[idx].rev, data))
+    }
+
+    /// Every revision of the file at `path`, oldest first. Works for deleted files too.
+    pub fn log(&self, path: &Path) -> Result<Vec<Revision>, crate::error::Error> {
+        match self.0.read().unwrap().nodes.get(path) {
+            Some(INode::File(_, history) | INode::Deleted(_, history)) => Ok(history.clone()),
+            _ => Err(crate::error::Error::Log),
+        }
+    }
+
+    /// Delete the file at `path`, leaving a tombstone so its revisions can still be fetched by
+    /// number.
+    pub fn delete(&mut self, path: &Path) -> Result<(), crate::error::Error> {
+        let mut lock = self.0.write().unwrap();
+
+        if !matches!(lock.nodes.get(path), Some(INode::File(..))) {
+            return Err(crate::error::Error::Delete);
+        }
+
+        let time = now();
+        lock.manifest
+            .append(&Record::Delete {
+                path: path.to_path_buf(),
+                time,
+            })
+            .map_err(storage_err)?;
+
+        lock.tombstone(path);
+        lock.commit(time, Change::Delete(path.to_path_buf()));
+
+        Ok(())
+    }
+
+    /// Move the file at `from` to `to` along with its history, returning its latest revision. `to`
+    /// must not exist, unless it's a deleted file in which case its history is discarded.
+    pub fn rename(&mut self, from: &Path, to: &Path) -> Result<usize, crate::error::Error> {
+        let mut lock = self.0.write().unwrap();
+
+        let rev = match lock.nodes.get(from) {
+            Some(INode::File(_, history)) => history.last().map(|r| r.rev),
+            _ => None,
+        }
+        .ok_or(crate::error::Error::Move)?;
+
+        if from == to || matches!(lock.nodes.get(to), Some(INode::File(..) | INode::Dir(_))) {
+            return Err(crate::error::Error::Move);
+        }
 
-                let data = fs::read(format!("{REPO_BASE}/{}", filename.as_simple()))
-                    .map_err(|_| crate::error::Error::Get)?;
+        if let Some(INode::File(_, history)) = lock.nodes.get(from) {
+            let size = history.last().map_or(0, |r| r.size);
+            check_quotas(
+                &lock.options.quotas,
+                &lock.usage,
+                &lock.nodes,
+                to,
+                size,
+                Some(from),
+            )?;
+        }
+
+        let time = now();
+        lock.manifest
+            .append(&Record::Move {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+                time,
+            })
+            .map_err(storage_err)?;
 
-                Ok((
-                    *rev,
-                    String::from_utf8(data).map_err(|_| crate::error::Error::Get)?,
-                ))
+        for revision in lock.relocate(from, to) {
+            // The move has happened, so a blob left behind is only swept up on the next open
+            if let Err(err) = lock.store.release(&revision.blob()) {
+                tracing::warn!(%err, "failed to remove replaced blob");
             }
-            INode::Dir(_) => unimplemented!(),
         }
+        lock.commit(time, Change::Move(from.to_path_buf(), to.to_path_buf()));
+
+        Ok(rev)
+    }
+
+    /// A unified diff of the file at `path` going from revision `from` to `to`. Both must be text.
+    pub fn diff(&self, path: &Path, from: usize, to: usize) -> Result<String, crate::error::Error> {
+        let (from, from_data) = self.get(path, from).map_err(diff_err)?;
+        let (to, to_data) = self.get(path, to).map_err(diff_err)?;
+
+        let (Ok(from_data), Ok(to_data)) =
+            (String::from_utf8(from_data), String::from_utf8(to_data))
+        else {
+            return Err(crate::error::Error::Binary);
+        };
+
+        let path = path.to_string_lossy();
+
+        Ok(similar::TextDiff::from_lines(&from_data, &to_data)
+            .unified_diff()
+            .header(&format!("{path} r{from}"), &format!("{path} r{to}"))
+            .to_string())
     }
 
     pub fn list(&self, path: &Path) -> Vec<INode> {
-        let expected_len = path.iter().count() + 1;
+        children(&self.0.read().unwrap().nodes, path)
+    }
 
-        let mut entries = self
-            .0
-            .read()
-            .unwrap()
+    /// Everything below the dir at `path`, with its full path. Each dir's entries are listed in
+    /// order, each subdir's directly after it.
+    pub fn walk(&self, path: &Path) -> Vec<(PathBuf, INode)> {
+        let mut entries = Vec::new();
+        walk(&self.0.read().unwrap().nodes, path, &mut entries);
+
+        entries
+    }
+
+    /// Record the repo as it stands under the tag `name`, returning the commit it points at.
+    pub fn tag(&mut self, name: &str) -> Result<usize, crate::error::Error> {
+        let mut lock = self.0.write().unwrap();
+
+        if lock.tags.contains_key(name) {
+            return Err(crate::error::Error::TagExists);
+        }
+
+        let record = Record::Tag {
+            name: name.to_string(),
+            commit: lock.commits.len(),
+        };
+        lock.manifest.append(&record).map_err(storage_err)?;
+        lock.replay(record);
+
+        Ok(lock.commits.len())
+    }
+
+    /// As [`Self::get`] for the latest revision, but of the file at `path` in a past snapshot.
+    pub fn get_at(
+        &self,
+        path: &Path,
+        at: &Snapshot,
+    ) -> Result<(usize, Vec<u8>), crate::error::Error> {
+        let lock = self.0.read().unwrap();
+
+        let (files, lineages) = lock.lineage(lock.resolve(at)?);
+        let (id, revision) = files.get(path).ok_or(crate::error::Error::Get)?;
+
+        // Find where the file's history lives now, unless a move has since replaced it, and the
+        // revision in it unless that's since expired
+        let history = match lineages.get(id).and_then(|now| lock.nodes.get(now)) {
+            Some(INode::File(_, history) | INode::Deleted(_, history)) => history,
+            _ => return Err(crate::error::Error::Get),
+        };
+        let idx = history
             .iter()
-            .filter_map(|(p, i)| {
-                (p.iter().count() == expected_len && p.starts_with(path)).then_some(i)
+            .position(|r| r.rev == revision.rev && r.content == revision.content)
+            .ok_or(crate::error::Error::Get)?;
+
+        let data = read_rev(&lock.store, history, idx).map_err(storage_err)?;
+
+        Ok((revision.rev, data))
+    }
+
+    /// As [`Self::list`], in a past snapshot.
+    pub fn list_at(&self, path: &Path, at: &Snapshot) -> Result<Vec<INode>, crate::error::Error> {
+        let lock = self.0.read().unwrap();
+
+        Ok(children(&lock.view(lock.resolve(at)?), path))
+    }
+
+    /// As [`Self::walk`], in a past snapshot.
+    pub fn walk_at(
+        &self,
+        path: &Path,
+        at: &Snapshot,
+    ) -> Result<Vec<(PathBuf, INode)>, crate::error::Error> {
+        let lock = self.0.read().unwrap();
+        let mut entries = Vec::new();
+        walk(&lock.view(lock.resolve(at)?), path, &mut entries);
+
+        Ok(entries)
+    }
+}
+
+/// The file node at `path` in `nodes`, creating it and any missing parent dirs.
+fn file_entry<'a>(nodes: &'a mut Index<INode>, path: &Path) -> &'a mut INode {
+    nodes.get_or_insert_with(path, |p| match p == path {
+        true => INode::new_file(p),
+        false => INode::new_dir(p),
+    })
+}
+
+/// The live entries directly inside the dir at `path`, in order.
+fn children(nodes: &Index<INode>, path: &Path) -> Vec<INode> {
+    nodes
+        .children(path)
+        .map(|(_, i)| i)
+        .filter(|i| !matches!(i, INode::Deleted(..)))
+        .cloned()
+        .collect()
+}
+
+fn walk(nodes: &Index<INode>, dir: &Path, entries: &mut Vec<(PathBuf, INode)>) {
+    for inode in children(nodes, dir) {
+        let path = dir.join(inode.name());
+        let is_dir = matches!(inode, INode::Dir(_));
+
+        entries.push((path.clone(), inode));
+
+        if is_dir {
+            walk(nodes, &path, entries);
+        }
+    }
+}
+
+/// Check a file of `size` bytes at `path` keeps every dir above it within its quota. The file
+/// already at `path`, and at `moving` when it's being moved there, don't count towards usage.
+fn check_quotas(
+    quotas: &[Quota],
+    usage: &[Usage],
+    nodes: &Index<INode>,
+    path: &Path,
+    size: usize,
+    moving: Option<&Path>,
+) -> Result<(), crate::error::Error> {
+    let existing = live_size(nodes, path);
+    let moved = moving.and_then(|from| live_size(nodes, from));
+
+    for (quota, usage) in quotas.iter().zip(usage) {
+        if !path.starts_with(&quota.dir) {
+            continue;
+        }
+
+        let mut usage = *usage;
+        usage.replace(existing, Some(size));
+        if moving.is_some_and(|from| from.starts_with(&quota.dir)) {
+            usage.replace(moved, None);
+        }
+
+        if !usage.within(quota) {
+            return Err(crate::error::Error::Quota);
+        }
+    }
+
+    Ok(())
+}
+
+/// The size of the latest revision of the live file at `path`, if there is one.
+fn live_size(nodes: &Index<INode>, path: &Path) -> Option<usize> {
+    match nodes.get(path) {
+        Some(INode::File(_, history)) => history.last().map(|r| r.size),
+        _ => None,
+    }
+}
+
+/// Rebuild the contents of `history[idx]` from the nearest snapshot at or before it, checking each
+/// step against its content hash.
+fn read_rev(store: &BlobStore, history: &[Revision], idx: usize) -> io::Result<Vec<u8>> {
+    let start = history[..=idx]
+        .iter()
+        .rposition(|r| r.delta.is_none())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no snapshot before revision"))?;
+
+    let mut data = store.read(&history[start].content)?;
+
+    for revision in &history[start + 1..=idx] {
+        let delta = store.read(&revision.blob())?;
+        data = delta::apply(&data, &delta)?;
+
+        if BlobHash::of(&data) != revision.content {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("r{} doesn't match its content hash", revision.rev),
+            ));
+        }
+    }
+
+    Ok(data)
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn diff_err(err: crate::error::Error) -> crate::error::Error {
+    match err {
+        crate::error::Error::Get => crate::error::Error::Diff,
+        err => err,
+    }
+}
+
+fn storage_err(err: io::Error) -> crate::error::Error {
+    tracing::error!(%err, "repo storage failure");
+
+    match err.kind() {
+        io::ErrorKind::InvalidData => crate::error::Error::Corrupt,
+        _ => crate::error::Error::Storage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reopen_restores_history() {
+        let root = tempfile::tempdir().unwrap();
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        assert_eq!(repo.put(Path::new("/a/b.txt"), b"one\n", Mode::Text), Ok(1));
+        assert_eq!(repo.put(Path::new("/a/b.txt"), b"two\n", Mode::Text), Ok(2));
+        assert_eq!(repo.put(Path::new("/a/b.txt"), b"two\n", Mode::Text), Ok(2));
+        assert_eq!(repo.put(Path::new("/c.txt"), b"three\n", Mode::Text), Ok(1));
+        drop(repo);
+
+        let repo = Repo::open(root.path(), Options::default()).unwrap();
+        assert_eq!(repo.get(Path::new("/a/b.txt"), 1), Ok((1, "one\n".into())));
+        assert_eq!(
+            repo.get(Path::new("/a/b.txt"), usize::MAX),
+            Ok((2, "two\n".into()))
+        );
+        assert_eq!(
+            repo.list(Path::new("/"))
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec!["a/ DIR\n", "c.txt r1\n"]
+        );
+    }
+
+    #[test]
+    fn torn_manifest_tail_is_dropped() {
+        let root = tempfile::tempdir().unwrap();
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        repo.put(Path::new("/a.txt"), b"one\n", Mode::Text).unwrap();
+        drop(repo);
+
+        let mut manifest = std::fs::OpenOptions::new()
+            .append(true)
+            .open(root.path().join("manifest"))
+            .unwrap();
+        std::io::Write::write_all(&mut manifest, br#"{"op":"put","path":"/a.t"#).unwrap();
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        assert_eq!(repo.put(Path::new("/a.txt"), b"two\n", Mode::Text), Ok(2));
+        drop(repo);
+
+        let repo = Repo::open(root.path(), Options::default()).unwrap();
+        assert_eq!(
+            repo.get(Path::new("/a.txt"), usize::MAX),
+            Ok((2, "two\n".into()))
+        );
+    }
+
+    #[test]
+    fn identical_content_is_stored_once() {
+        let root = tempfile::tempdir().unwrap();
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        repo.put(Path::new("/a.txt"), b"same\n", Mode::Text)
+            .unwrap();
+        repo.put(Path::new("/b.txt"), b"same\n", Mode::Text)
+            .unwrap();
+        repo.put(Path::new("/a.txt"), b"other\n", Mode::Text)
+            .unwrap();
+        repo.put(Path::new("/a.txt"), b"same\n", Mode::Text)
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read_dir(root.path().join("blobs"))
+                .unwrap()
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn corrupt_blob_is_an_error() {
+        let root = tempfile::tempdir().unwrap();
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        repo.put(Path::new("/a.txt"), b"good\n", Mode::Text)
+            .unwrap();
+
+        let blob = root
+            .path()
+            .join("blobs")
+            .join(BlobHash::of(b"good\n").to_string());
+        std::fs::write(blob, b"evil\n").unwrap();
+
+        assert_eq!(
+            repo.get(Path::new("/a.txt"), 1),
+            Err(crate::error::Error::Corrupt)
+        );
+    }
+
+    #[test]
+    fn every_revision_round_trips_through_deltas() {
+        let root = tempfile::tempdir().unwrap();
+        let options = Options {
+            snapshot_interval: 4,
+            ..Default::default()
+        };
+        let path = Path::new("/big.txt");
+
+        let mut lines = (0..200).map(|n| format!("line {n}\n")).collect::<Vec<_>>();
+        let mut versions = Vec::new();
+
+        let mut repo = Repo::open(root.path(), options.clone()).unwrap();
+        for n in 0..20 {
+            match n % 4 {
+                0 => lines[n * 7] = format!("edited {n}\n"),
+                1 => lines.insert(n * 3, format!("inserted {n}\n")),
+                2 => drop(lines.remove(n * 5)),
+                _ => lines.push(format!("appended {n}")),
+            }
+            let contents = lines.concat();
+
+            assert_eq!(repo.put(path, contents.as_bytes(), Mode::Text), Ok(n + 1));
+            versions.push(contents);
+
+            // Undo the missing trailing newline so later edits stay line based
+            if n % 4 == 3 {
+                lines.last_mut().unwrap().push('\n');
+            }
+        }
+
+        let stored_deltas = match repo.0.read().unwrap().nodes.get(path) {
+            Some(INode::File(_, history)) => history.iter().filter(|r| r.delta.is_some()).count(),
+            _ => panic!("file missing"),
+        };
+        assert_eq!(stored_deltas, 15);
+
+        let check = |repo: &Repo| {
+            for (n, contents) in versions.iter().enumerate() {
+                assert_eq!(
+                    repo.get(path, n + 1),
+                    Ok((n + 1, contents.clone().into_bytes()))
+                );
+            }
+        };
+
+        check(&repo);
+        drop(repo);
+        check(&Repo::open(root.path(), options).unwrap());
+    }
+
+    #[test]
+    fn known_contents_are_not_stored_as_deltas() {
+        let root = tempfile::tempdir().unwrap();
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        let shared = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        repo.put(Path::new("/a.txt"), shared.as_bytes(), Mode::Text)
+            .unwrap();
+        repo.put(Path::new("/b.txt"), b"a\nb\nc\nd\ne\nf\ng\n", Mode::Text)
+            .unwrap();
+        repo.put(Path::new("/b.txt"), shared.as_bytes(), Mode::Text)
+            .unwrap();
+
+        match repo.0.read().unwrap().nodes.get(Path::new("/b.txt")) {
+            Some(INode::File(_, history)) => assert_eq!(history[1].delta, None),
+            _ => panic!("file missing"),
+        };
+        assert_eq!(repo.get(Path::new("/b.txt"), 2), Ok((2, shared.into())));
+    }
+
+    #[test]
+    fn log_and_diff() {
+        let root = tempfile::tempdir().unwrap();
+        let path = Path::new("/a.txt");
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        repo.put(path, b"one\ntwo\nthree\n", Mode::Text).unwrap();
+        repo.put(path, b"one\n2\nthree\n", Mode::Text).unwrap();
+
+        let log = repo.log(path).unwrap();
+        assert_eq!(
+            log.iter().map(|r| (r.rev, r.size)).collect::<Vec<_>>(),
+            vec![(1, 14), (2, 12)]
+        );
+        assert!(log[1].time >= log[0].time && log[0].time > 0);
+
+        assert_eq!(
+            repo.diff(path, 1, 2).unwrap(),
+            "--- /a.txt r1\n+++ /a.txt r2\n@@ -1,3 +1,3 @@\n one\n-two\n+2\n three\n"
+        );
+        assert_eq!(repo.diff(path, 1, 3), Err(crate::error::Error::Diff));
+        assert_eq!(repo.log(Path::new("/b.txt")), Err(crate::error::Error::Log));
+    }
+
+    #[test]
+    fn delete_keeps_old_revisions() {
+        let root = tempfile::tempdir().unwrap();
+        let path = Path::new("/a/b/c.txt");
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        repo.put(path, b"one\n", Mode::Text).unwrap();
+        repo.put(path, b"two\n", Mode::Text).unwrap();
+        repo.put(Path::new("/d.txt"), b"d\n", Mode::Text).unwrap();
+
+        assert_eq!(repo.delete(path), Ok(()));
+        assert_eq!(repo.delete(path), Err(crate::error::Error::Delete));
+        assert_eq!(
+            repo.delete(Path::new("/a")),
+            Err(crate::error::Error::Delete)
+        );
+
+        let check = |repo: &Repo| {
+            assert_eq!(repo.get(path, usize::MAX), Err(crate::error::Error::Get));
+            assert_eq!(repo.get(path, 1), Ok((1, "one\n".into())));
+            assert_eq!(repo.log(path).unwrap().len(), 2);
+            assert!(repo.list(Path::new("/a/b")).is_empty());
+            assert_eq!(
+                repo.list(Path::new("/"))
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+                vec!["d.txt r1\n"]
+            );
+        };
+
+        check(&repo);
+        drop(repo);
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        check(&repo);
+
+        // Putting the same contents again still brings it back
+        assert_eq!(repo.put(path, b"two\n", Mode::Text), Ok(3));
+        assert_eq!(repo.get(path, usize::MAX), Ok((3, "two\n".into())));
+        assert_eq!(repo.list(Path::new("/")).len(), 2);
+    }
+
+    #[test]
+    fn move_carries_history() {
+        let root = tempfile::tempdir().unwrap();
+        let (from, to) = (Path::new("/a/x.txt"), Path::new("/b/y.txt"));
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        repo.put(from, b"one\n", Mode::Text).unwrap();
+        repo.put(from, b"two\n", Mode::Text).unwrap();
+        repo.put(Path::new("/c.txt"), b"c\n", Mode::Text).unwrap();
+
+        assert_eq!(repo.rename(from, to), Ok(2));
+        assert_eq!(repo.rename(from, to), Err(crate::error::Error::Move));
+        assert_eq!(
+            repo.rename(Path::new("/c.txt"), to),
+            Err(crate::error::Error::Move)
+        );
+        assert_eq!(
+            repo.rename(Path::new("/c.txt"), Path::new("/b")),
+            Err(crate::error::Error::Move)
+        );
+
+        let check = |repo: &Repo| {
+            assert_eq!(repo.get(from, 1), Err(crate::error::Error::Get));
+            assert_eq!(repo.get(to, 1), Ok((1, "one\n".into())));
+            assert_eq!(
+                repo.list(Path::new("/"))
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>(),
+                vec!["b/ DIR\n", "c.txt r1\n"]
+            );
+            assert_eq!(repo.list(Path::new("/b"))[0].to_string(), "y.txt r2\n");
+        };
+
+        check(&repo);
+        drop(repo);
+        check(&Repo::open(root.path(), Options::default()).unwrap());
+    }
+
+    #[test]
+    fn move_over_deleted_file_drops_its_blobs() {
+        let root = tempfile::tempdir().unwrap();
+        let blobs = || {
+            std::fs::read_dir(root.path().join("blobs"))
+                .unwrap()
+                .count()
+        };
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        repo.put(Path::new("/old.txt"), b"old\n", Mode::Text)
+            .unwrap();
+        repo.put(Path::new("/new.txt"), b"new\n", Mode::Text)
+            .unwrap();
+        repo.delete(Path::new("/old.txt")).unwrap();
+        assert_eq!(blobs(), 2);
+
+        assert_eq!(
+            repo.rename(Path::new("/new.txt"), Path::new("/old.txt")),
+            Ok(1)
+        );
+        assert_eq!(blobs(), 1);
+        drop(repo);
+
+        let repo = Repo::open(root.path(), Options::default()).unwrap();
+        assert_eq!(
+            repo.get(Path::new("/old.txt"), usize::MAX),
+            Ok((1, "new\n".into()))
+        );
+        assert_eq!(repo.log(Path::new("/old.txt")).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn binary_mode() {
+        let root = tempfile::tempdir().unwrap();
+        let path = Path::new("/blob.bin");
+        let data = [0, 159, 146, 150, 255, 10];
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        assert_eq!(
+            repo.put(path, &data, Mode::Text),
+            Err(crate::error::Error::Put)
+        );
+        assert_eq!(
+            repo.put(path, b"bell\x07\n", Mode::Text),
+            Err(crate::error::Error::Put)
+        );
+        assert_eq!(repo.put(path, &data, Mode::Binary), Ok(1));
+        assert_eq!(repo.put(path, b"text\n", Mode::Binary), Ok(2));
+
+        assert_eq!(repo.get(path, 1), Ok((1, data.to_vec())));
+        assert!(!Mode::Text.allows(&repo.get(path, 1).unwrap().1));
+        assert_eq!(repo.diff(path, 1, 2), Err(crate::error::Error::Binary));
+    }
+
+    #[test]
+    fn walk_is_depth_first_in_order() {
+        let root = tempfile::tempdir().unwrap();
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        for path in [
+            "/src/main.rs",
+            "/src/a/b.rs",
+            "/README",
+            "/src/a.rs",
+            "/gone.txt",
+        ] {
+            repo.put(Path::new(path), b"x\n", Mode::Text).unwrap();
+        }
+        repo.delete(Path::new("/gone.txt")).unwrap();
+
+        assert_eq!(
+            repo.walk(Path::new("/"))
+                .iter()
+                .map(|(p, i)| format!("{} {i}", p.display()))
+                .collect::<Vec<_>>(),
+            vec![
+                "/README README r1\n",
+                "/src src/ DIR\n",
+                "/src/a a/ DIR\n",
+                "/src/a/b.rs b.rs r1\n",
+                "/src/a.rs a.rs r1\n",
+                "/src/main.rs main.rs r1\n",
+            ]
+        );
+        assert_eq!(repo.walk(Path::new("/src/a")).len(), 1);
+        assert!(repo.walk(Path::new("/nope")).is_empty());
+    }
+
+    #[test]
+    fn snapshots_follow_moves_and_deletes() {
+        let root = tempfile::tempdir().unwrap();
+        let (a, b, c) = (
+            Path::new("/a.txt"),
+            Path::new("/b.txt"),
+            Path::new("/d/c.txt"),
+        );
+        let v1 = Snapshot::Tag("v1".into());
+        let v2 = Snapshot::Tag("v2".into());
+
+        let mut repo = Repo::open(root.path(), Options::default()).unwrap();
+        repo.put(a, b"a1\n", Mode::Text).unwrap();
+        repo.put(b, b"b1\n", Mode::Text).unwrap();
+        assert_eq!(repo.tag("v1"), Ok(2));
+        assert_eq!(repo.tag("v1"), Err(crate::error::Error::TagExists));
+
+        repo.put(a, b"a2\n", Mode::Text).unwrap();
+        repo.rename(a, c).unwrap();
+        repo.delete(b).unwrap();
+        assert_eq!(repo.tag("v2"), Ok(5));
+
+        let names = |list: Vec<(PathBuf, INode)>| {
+            list.iter()
+                .map(|(p, i)| format!("{} {i}", p.display()))
+                .collect::<Vec<_>>()
+        };
+
+        let check = |repo: &Repo| {
+            assert_eq!(repo.get_at(a, &v1), Ok((1, b"a1\n".to_vec())));
+            assert_eq!(repo.get_at(b, &v1), Ok((1, b"b1\n".to_vec())));
+            assert_eq!(repo.get_at(c, &v1), Err(crate::error::Error::Get));
+            assert_eq!(repo.get_at(c, &v2), Ok((2, b"a2\n".to_vec())));
+            assert_eq!(repo.get_at(b, &v2), Err(crate::error::Error::Get));
+
+            assert_eq!(
+                names(repo.walk_at(Path::new("/"), &v1).unwrap()),
+                vec!["/a.txt a.txt r1\n", "/b.txt b.txt r1\n"]
+            );
+            assert_eq!(
+                names(repo.walk_at(Path::new("/"), &v2).unwrap()),
+                vec!["/d d/ DIR\n", "/d/c.txt c.txt r2\n"]
+            );
+            assert_eq!(
+                repo.list_at(Path::new("/"), &Snapshot::Tag("v3".into())),
+                Err(crate::error::Error::NoSuchTag)
+            );
+
+            assert!(repo
+                .list_at(Path::new("/"), &Snapshot::Time(0))
+                .unwrap()
+                .is_empty());
+            assert_eq!(
+                repo.list_at(Path::new("/"), &Snapshot::Time(u64::MAX)),
+                Ok(repo.list(Path::new("/")))
+            );
+        };
+
+        check(&repo);
+        drop(repo);
+        check(&Repo::open(root.path(), Options::default()).unwrap());
+    }
+
+    #[test]
+    fn quotas_are_enforced() {
+        let root = tempfile::tempdir().unwrap();
+        let options = Options {
+            quotas: vec!["/small:10:".parse().unwrap(), "/few::2".parse().unwrap()],
+            ..Default::default()
+        };
+
+        let mut repo = Repo::open(root.path(), options.clone()).unwrap();
+        let small = Path::new("/small/a.txt");
+        assert_eq!(repo.put(small, b"12345\n", Mode::Text), Ok(1));
+        assert_eq!(repo.put(small, b"123456789\n", Mode::Text), Ok(2));
+        assert_eq!(
+            repo.put(small, b"1234567890\n", Mode::Text),
+            Err(crate::error::Error::Quota)
+        );
+        assert_eq!(
+            repo.put(Path::new("/small/b.txt"), b"1\n", Mode::Text),
+            Err(crate::error::Error::Quota)
+        );
+
+        repo.put(Path::new("/few/a"), b"a\n", Mode::Text).unwrap();
+        repo.put(Path::new("/few/b/c"), b"c\n", Mode::Text).unwrap();
+        assert_eq!(repo.put(Path::new("/few/a"), b"aa\n", Mode::Text), Ok(2));
+        assert_eq!(
+            repo.put(Path::new("/few/d"), b"d\n", Mode::Text),
+            Err(crate::error::Error::Quota)
+        );
+        assert_eq!(
+            repo.rename(Path::new("/small/a.txt"), Path::new("/few/d")),
+            Err(crate::error::Error::Quota)
+        );
+        assert_eq!(repo.rename(Path::new("/few/a"), Path::new("/few/d")), Ok(2));
+
+        repo.delete(Path::new("/few/d")).unwrap();
+        assert_eq!(repo.put(Path::new("/few/e"), b"e\n", Mode::Text), Ok(1));
+        assert_eq!(repo.put(Path::new("/other"), b"x\n", Mode::Text), Ok(1));
+        drop(repo);
+
+        // Usage is counted back up from the manifest
+        let mut repo = Repo::open(root.path(), options).unwrap();
+        assert_eq!(
+            repo.put(Path::new("/small/b.txt"), b"1\n", Mode::Text),
+            Err(crate::error::Error::Quota)
+        );
+        assert_eq!(
+            repo.put(Path::new("/few/f"), b"f\n", Mode::Text),
+            Err(crate::error::Error::Quota)
+        );
+        repo.delete(Path::new("/few/e")).unwrap();
+        assert_eq!(repo.put(Path::new("/few/f"), b"f\n", Mode::Text), Ok(1));
+    }
+
+    #[test]
+    fn compaction_keeps_recent_revisions() {
+        let root = tempfile::tempdir().unwrap();
+        let options = || Options {
+            retention: Retention {
+                keep_last: Some(2),
+                keep_for: None,
+            },
+            ..Default::default()
+        };
+        let path = Path::new("/a.txt");
+        let blobs = || {
+            std::fs::read_dir(root.path().join("blobs"))
+                .unwrap()
+                .count()
+        };
+
+        let mut repo = Repo::open(root.path(), options()).unwrap();
+        let versions = (1..=5)
+            .map(|n| {
+                (1..=20)
+                    .map(|l| format!("{} {l}\n", l == n))
+                    .collect::<String>()
             })
-            .cloned()
             .collect::<Vec<_>>();
+        for version in &versions {
+            repo.put(path, version.as_bytes(), Mode::Text).unwrap();
+        }
+        repo.put(Path::new("/gone.txt"), b"gone\n", Mode::Text)
+            .unwrap();
+        repo.delete(Path::new("/gone.txt")).unwrap();
+        assert_eq!(blobs(), 6);
+        assert!(repo.log(path).unwrap()[3].delta.is_some());
 
-        entries.sort();
+        // Both of /a.txt's and /gone.txt's only revision are kept
+        assert_eq!(repo.compact(), Ok(3));
+        assert_eq!(repo.compact(), Ok(0));
+        assert_eq!(blobs(), 3);
+        assert_eq!(repo.get(path, 3), Err(crate::error::Error::Get));
+        assert_eq!(repo.get(path, 4), Ok((4, versions[3].clone().into())));
+        assert_eq!(repo.get(path, 5), Ok((5, versions[4].clone().into())));
+        drop(repo);
 
-        entries
+        let mut repo = Repo::open(root.path(), options()).unwrap();
+        assert_eq!(blobs(), 3);
+        assert_eq!(
+            repo.log(path)
+                .unwrap()
+                .iter()
+                .map(|r| r.rev)
+                .collect::<Vec<_>>(),
+            vec![4, 5]
+        );
+        assert_eq!(repo.get(path, 4), Ok((4, versions[3].clone().into())));
+        assert_eq!(repo.put(path, b"six\n", Mode::Text), Ok(6));
+        assert_eq!(repo.compact(), Ok(1));
+        assert_eq!(repo.get(path, 5), Ok((5, versions[4].clone().into())));
+    }
+
+    #[test]
+    fn compaction_drops_expired_tombstones() {
+        let root = tempfile::tempdir().unwrap();
+        let options = Options {
+            retention: Retention {
+                keep_last: None,
+                keep_for: Some(Duration::ZERO),
+            },
+            ..Default::default()
+        };
+
+        let mut repo = Repo::open(root.path(), options).unwrap();
+        repo.put(Path::new("/a/b.txt"), b"one\n", Mode::Text)
+            .unwrap();
+        repo.put(Path::new("/a/b.txt"), b"two\n", Mode::Text)
+            .unwrap();
+        repo.put(Path::new("/c.txt"), b"three\n", Mode::Text)
+            .unwrap();
+        repo.delete(Path::new("/c.txt")).unwrap();
+
+        assert_eq!(repo.compact(), Ok(2));
+        assert_eq!(repo.log(Path::new("/c.txt")), Err(crate::error::Error::Log));
+        assert_eq!(
+            repo.get(Path::new("/a/b.txt"), usize::MAX),
+            Ok((2, "two\n".into()))
+        );
+    }
+
+    #[test]
+    fn revisions_carry_on_after_tombstones_expire() {
+        let root = tempfile::tempdir().unwrap();
+        let options = Options {
+            retention: Retention {
+                keep_last: None,
+                keep_for: Some(Duration::ZERO),
+            },
+            ..Default::default()
+        };
+        let path = Path::new("/a.txt");
+        let v1 = Snapshot::Tag("v1".into());
+
+        let mut repo = Repo::open(root.path(), options.clone()).unwrap();
+        assert_eq!(repo.put(path, b"old\n", Mode::Text), Ok(1));
+        repo.tag("v1").unwrap();
+        repo.delete(path).unwrap();
+        assert_eq!(repo.compact(), Ok(1));
+        assert_eq!(repo.put(path, b"new\n", Mode::Text), Ok(2));
+
+        let check = |repo: &Repo| {
+            assert_eq!(repo.get_at(path, &v1), Err(crate::error::Error::Get));
+            assert_eq!(repo.get(path, 1), Err(crate::error::Error::Get));
+            assert_eq!(repo.get(path, usize::MAX), Ok((2, b"new\n".to_vec())));
+        };
+
+        check(&repo);
+        drop(repo);
+        check(&Repo::open(root.path(), options).unwrap());
+    }
+
+    #[test]
+    fn reads_survive_compaction() {
+        let root = tempfile::tempdir().unwrap();
+        let options = Options {
+            snapshot_interval: 4,
+            retention: Retention {
+                keep_last: Some(1),
+                keep_for: None,
+            },
+            ..Default::default()
+        };
+        let path = Path::new("/a.txt");
+
+        let mut repo = Repo::open(root.path(), options).unwrap();
+        repo.put(path, b"0\n", Mode::Text).unwrap();
+
+        let reader = {
+            let repo = repo.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    let (rev, data) = repo.get(path, usize::MAX).unwrap();
+                    assert_eq!(data, format!("{}\n", rev - 1).into_bytes());
+                }
+            })
+        };
+
+        for n in 1..200 {
+            repo.put(path, format!("{n}\n").as_bytes(), Mode::Text)
+                .unwrap();
+            repo.compact().unwrap();
+        }
+
+        reader.join().unwrap();
     }
 }
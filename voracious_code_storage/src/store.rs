@@ -0,0 +1,236 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use uuid::Uuid;
+
+/// SHA-256 of a blob's contents, which is also its name in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct BlobHash([u8; 32]);
+
+impl BlobHash {
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+}
+
+impl Display for BlobHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.0 {
+            write!(f, "{b:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for BlobHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(format!("not a sha256 hex digest: {s}"));
+        }
+
+        let mut hash = [0; 32];
+        for (n, b) in hash.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[n * 2..n * 2 + 2], 16).map_err(|e| e.to_string())?;
+        }
+
+        Ok(Self(hash))
+    }
+}
+
+impl TryFrom<String> for BlobHash {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<BlobHash> for String {
+    fn from(hash: BlobHash) -> Self {
+        hash.to_string()
+    }
+}
+
+/// Content-addressed file revision contents under `<root>/blobs`. Identical contents are stored
+/// once, and reference counted so a blob is removed when nothing points at it any more.
+#[derive(Debug)]
+pub struct BlobStore {
+    blobs: PathBuf,
+    tmp: PathBuf,
+    refs: HashMap<BlobHash, usize>,
+}
+
+impl BlobStore {
+    pub fn open(root: &Path) -> io::Result<Self> {
+        let blobs = root.join("blobs");
+        let tmp = root.join("tmp");
+
+        // Anything left in tmp is from a write that never made it into the manifest
+        fs::remove_dir_all(&tmp).ok();
+        fs::create_dir_all(&blobs)?;
+        fs::create_dir_all(&tmp)?;
+
+        Ok(Self {
+            blobs,
+            tmp,
+            refs: HashMap::new(),
+        })
+    }
+
+    /// Store `data` (if it isn't already) and take a reference to it.
+    pub fn write(&mut self, data: &[u8]) -> io::Result<BlobHash> {
+        let hash = self.save(data)?;
+        self.retain(hash);
+
+        Ok(hash)
+    }
+
+    /// Store `data` if it isn't already, without taking a reference. Until one is taken it is
+    /// liable to be swept up.
+    pub fn save(&self, data: &[u8]) -> io::Result<BlobHash> {
+        let hash = BlobHash::of(data);
+
+        if !self.refs.contains_key(&hash) {
+            durable_write(&self.tmp, &self.path(&hash), data)?;
+        }
+
+        Ok(hash)
+    }
+
+    pub fn contains(&self, hash: &BlobHash) -> bool {
+        self.refs.contains_key(hash)
+    }
+
+    /// Take a reference to an existing blob, e.g. when replaying the manifest.
+    pub fn retain(&mut self, hash: BlobHash) {
+        *self.refs.entry(hash).or_default() += 1;
+    }
+
+    /// Drop a reference, deleting the blob once none remain.
+    pub fn release(&mut self, hash: &BlobHash) -> io::Result<()> {
+        if self.forget(hash) {
+            fs::remove_file(self.path(hash))?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop a reference without touching the blob file, returning whether it was the last one.
+    /// Replay uses this as a later record may want the same blob; [`Self::sweep`] tidies up after.
+    pub fn forget(&mut self, hash: &BlobHash) -> bool {
+        match self.refs.get_mut(hash) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            Some(_) => {
+                self.refs.remove(hash);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove blob files nothing references, left behind by a crash before their manifest entry
+    /// was written. Call once every reference has been retained.
+    pub fn sweep(&self) -> io::Result<usize> {
+        let mut swept = 0;
+
+        for entry in fs::read_dir(&self.blobs)? {
+            let entry = entry?;
+            let referenced = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse::<BlobHash>().ok())
+                .is_some_and(|hash| self.refs.contains_key(&hash));
+
+            if !referenced {
+                fs::remove_file(entry.path())?;
+                swept += 1;
+            }
+        }
+
+        Ok(swept)
+    }
+
+    /// Read a blob, checking its contents still match its hash.
+    pub fn read(&self, hash: &BlobHash) -> io::Result<Vec<u8>> {
+        let data = fs::read(self.path(hash))?;
+
+        if BlobHash::of(&data) != *hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("blob {hash} is corrupt"),
+            ));
+        }
+
+        Ok(data)
+    }
+
+    fn path(&self, hash: &BlobHash) -> PathBuf {
+        self.blobs.join(hash.to_string())
+    }
+}
+
+/// Write `data` to `dest` so it is either fully present or absent after a crash: it is staged in
+/// `tmp`, flushed to disk and then renamed into place.
+fn durable_write(tmp: &Path, dest: &Path, data: &[u8]) -> io::Result<()> {
+    let staged = tmp.join(Uuid::new_v4().as_simple().to_string());
+
+    let mut file = File::create(&staged)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    fs::rename(&staged, dest)?;
+
+    if let Some(dir) = dest.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dedupes_and_refcounts() {
+        let root = tempfile::tempdir().unwrap();
+        let mut store = BlobStore::open(root.path()).unwrap();
+
+        let a = store.write(b"same").unwrap();
+        let b = store.write(b"same").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(fs::read_dir(root.path().join("blobs")).unwrap().count(), 1);
+
+        store.release(&a).unwrap();
+        assert_eq!(store.read(&a).unwrap(), b"same");
+
+        store.release(&b).unwrap();
+        assert!(store.read(&a).is_err());
+    }
+
+    #[test]
+    fn hash_round_trips_as_hex() {
+        let hash = BlobHash::of(b"abc");
+        let hex = hash.to_string();
+
+        assert_eq!(
+            hex,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(hex.parse::<BlobHash>(), Ok(hash));
+    }
+}